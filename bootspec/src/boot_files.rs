@@ -0,0 +1,202 @@
+//! Helpers shared by the bootloader backends for copying files out of the Nix store.
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::BootspecError;
+use crate::v1::BootSpecV1;
+use crate::validation::DEFAULT_STORE_DIR;
+use crate::{Result, SpecialisationName};
+
+/// A file that must be copied from the Nix store to the boot partition.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BootFile {
+    /// The file to copy.
+    pub source: PathBuf,
+    /// The destination, relative to the root of the boot partition.
+    pub destination: PathBuf,
+}
+
+/// A de-duplicated list of [`BootFile`]s.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct BootFiles(pub(crate) Vec<BootFile>);
+
+impl BootFiles {
+    /// Plan to copy `source` into `dir`, returning its destination.
+    ///
+    /// The file is named after its store path and its path inside it (see [`store_file_name`])
+    /// followed by `suffix`. Returns [`BootspecError::ConflictingBootFiles`] if a different file
+    /// was already planned for the same destination.
+    pub(crate) fn copy(&mut self, source: &Path, dir: &Path, suffix: &str) -> Result<PathBuf> {
+        self.copy_as(source, source, dir, suffix)
+    }
+
+    /// Plan to copy the initrd of `bootspec` into `dir`, returning its destination.
+    ///
+    /// If the bootspec has an `initrdSecrets` tool, the initrd with secrets created by it is
+    /// copied instead (see [`initrd`]), named after the tool.
+    pub(crate) fn copy_initrd(
+        &mut self,
+        bootspec: &BootSpecV1,
+        initrds_with_secrets: &BTreeMap<PathBuf, PathBuf>,
+        dir: &Path,
+        suffix: &str,
+    ) -> Result<Option<PathBuf>> {
+        let Some(source) = initrd(bootspec, initrds_with_secrets)? else {
+            return Ok(None);
+        };
+        let destination = match &bootspec.initrd_secrets {
+            Some(tool) => self.copy_as(source, tool, dir, &format!("-initrd{}", suffix))?,
+            None => self.copy(source, dir, suffix)?,
+        };
+
+        Ok(Some(destination))
+    }
+
+    /// Like [`BootFiles::copy`], but names the destination after `name` instead of `source`.
+    fn copy_as(&mut self, source: &Path, name: &Path, dir: &Path, suffix: &str) -> Result<PathBuf> {
+        let destination = dir.join(format!("{}{}", store_file_name(name)?, suffix));
+        match self.0.iter().find(|file| file.destination == destination) {
+            Some(file) if file.source != source => {
+                return Err(BootspecError::ConflictingBootFiles {
+                    destination,
+                    first: file.source.clone(),
+                    second: source.to_path_buf(),
+                })
+            }
+            Some(_) => {}
+            None => self.0.push(BootFile {
+                source: source.to_path_buf(),
+                destination: destination.clone(),
+            }),
+        }
+
+        Ok(destination)
+    }
+}
+
+/// The initrd `bootspec` should boot with.
+///
+/// If the bootspec has an `initrdSecrets` tool, this is the initrd the tool was run against
+/// (see [`BootSpecV1::append_initrd_secrets`]), looked up by the tool's path in
+/// `initrds_with_secrets`. Booting the bare initrd instead would boot without the secrets, so
+/// a missing entry is an error.
+pub(crate) fn initrd<'a>(
+    bootspec: &'a BootSpecV1,
+    initrds_with_secrets: &'a BTreeMap<PathBuf, PathBuf>,
+) -> Result<Option<&'a Path>> {
+    match &bootspec.initrd_secrets {
+        Some(tool) => initrds_with_secrets
+            .get(tool)
+            .map(|initrd| Some(initrd.as_path()))
+            .ok_or_else(|| BootspecError::MissingInitrdWithSecrets(tool.clone())),
+        None => Ok(bootspec.initrd.as_deref()),
+    }
+}
+
+/// The part of an entry's identifier (e.g. its file name or `LABEL`) naming specialisation
+/// `name`.
+///
+/// Returns [`BootspecError::InvalidSpecialisationName`] unless the name only consists of ASCII
+/// letters, digits, `-` and `_`, so that it cannot leave the entries directory or break the
+/// entry's syntax.
+pub(crate) fn entry_name(name: &SpecialisationName) -> Result<&str> {
+    let valid = !name.0.is_empty()
+        && name
+            .0
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+    if !valid {
+        return Err(BootspecError::InvalidSpecialisationName(name.0.clone()));
+    }
+
+    Ok(&name.0)
+}
+
+/// Name a store file after its store path and its path inside it, e.g.
+/// `/nix/store/xxx-linux-6.1/lib/bzImage` becomes `xxx-linux-6.1-lib-bzImage`, so that files
+/// from different store paths never collide. Files outside the store are named after their whole
+/// path.
+pub(crate) fn store_file_name(source: &Path) -> Result<String> {
+    let relative = source.strip_prefix(DEFAULT_STORE_DIR).unwrap_or(source);
+
+    let mut parts = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .ok_or_else(|| BootspecError::InvalidUtf8(source.to_path_buf()))?,
+            ),
+            Component::RootDir | Component::CurDir => {}
+            Component::Prefix(_) | Component::ParentDir => {
+                return Err(BootspecError::InvalidFileName(source.to_path_buf()))
+            }
+        }
+    }
+    if parts.is_empty() {
+        return Err(BootspecError::InvalidFileName(source.to_path_buf()));
+    }
+
+    Ok(parts.join("-"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{store_file_name, BootFiles};
+    use crate::error::BootspecError;
+
+    #[test]
+    fn names_include_the_whole_store_path() {
+        assert_eq!(
+            store_file_name(Path::new("/nix/store/aaa-linux/bzImage")).unwrap(),
+            "aaa-linux-bzImage"
+        );
+        assert_eq!(
+            store_file_name(Path::new("/nix/store/aaa-linux/lib/bzImage")).unwrap(),
+            "aaa-linux-lib-bzImage"
+        );
+        assert_eq!(
+            store_file_name(Path::new("/boot/initrd")).unwrap(),
+            "boot-initrd"
+        );
+        assert!(store_file_name(Path::new("/nix/store")).is_err());
+        assert!(store_file_name(Path::new("/nix/store/aaa-linux/../bzImage")).is_err());
+
+        let mut files = BootFiles::default();
+        let dir = Path::new("kernels");
+        let aaa = files
+            .copy(Path::new("/nix/store/aaa-linux/lib/bzImage"), dir, "")
+            .unwrap();
+        let bbb = files
+            .copy(Path::new("/nix/store/bbb-linux/lib/bzImage"), dir, "")
+            .unwrap();
+        assert_ne!(aaa, bbb);
+        files
+            .copy(Path::new("/nix/store/aaa-linux/lib/bzImage"), dir, "")
+            .unwrap();
+        assert_eq!(files.0.len(), 2);
+    }
+
+    #[test]
+    fn conflicting_names_are_an_error() {
+        let mut files = BootFiles::default();
+        let dir = Path::new("kernels");
+        files
+            .copy(Path::new("/nix/store/aaa-linux/lib-bzImage"), dir, "")
+            .unwrap();
+
+        match files.copy(Path::new("/nix/store/aaa-linux/lib/bzImage"), dir, "") {
+            Err(BootspecError::ConflictingBootFiles {
+                destination,
+                first,
+                second,
+            }) => {
+                assert_eq!(destination, PathBuf::from("kernels/aaa-linux-lib-bzImage"));
+                assert_eq!(first, PathBuf::from("/nix/store/aaa-linux/lib-bzImage"));
+                assert_eq!(second, PathBuf::from("/nix/store/aaa-linux/lib/bzImage"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
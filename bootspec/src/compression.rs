@@ -0,0 +1,138 @@
+//! Detection of the compression formats the kernel supports for itself and its initrd.
+//!
+//! With the `initrd` feature, most of them can also be decompressed.
+use std::fmt;
+#[cfg(feature = "initrd")]
+use std::io::{self, Read};
+
+const LZ4_LEGACY_MAGIC: &[u8] = b"\x02\x21\x4c\x18";
+
+/// A compression format, identified by its magic number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Lzma,
+    Xz,
+    Lzo,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    /// The compression format `data` starts with, if any.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        const MAGICS: &[(&[u8], Compression)] = &[
+            (b"\x1f\x8b", Compression::Gzip),
+            (b"BZh", Compression::Bzip2),
+            (b"\x5d\x00\x00", Compression::Lzma),
+            (b"\xfd7zXZ\x00", Compression::Xz),
+            (b"\x89LZO", Compression::Lzo),
+            // The legacy format, which is the only one the kernel supports.
+            (LZ4_LEGACY_MAGIC, Compression::Lz4),
+            (b"\x28\xb5\x2f\xfd", Compression::Zstd),
+        ];
+
+        MAGICS
+            .iter()
+            .find(|(magic, _)| data.starts_with(magic))
+            .map(|&(_, compression)| compression)
+    }
+
+    /// The compression format with the given name, as used by the kernel's build system, e.g.
+    /// `gzip` or `zstd22`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Compression::Gzip),
+            "bzip2" => Some(Compression::Bzip2),
+            "lzma" => Some(Compression::Lzma),
+            "xz" | "xzkern" => Some(Compression::Xz),
+            "lzo" => Some(Compression::Lzo),
+            "lz4" => Some(Compression::Lz4),
+            "zstd" | "zstd22" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "initrd")]
+impl Compression {
+    /// Decompress the single stream (e.g. one gzip member or one zstd frame) that `data` starts
+    /// with, returning the decompressed data and the number of bytes of `data` the stream took up.
+    ///
+    /// bzip2 and LZO are not supported and return an [`io::ErrorKind::Unsupported`] error.
+    pub fn decompress(self, data: &[u8]) -> io::Result<(Vec<u8>, usize)> {
+        let mut input = data;
+        let mut output = Vec::new();
+
+        match self {
+            Compression::Gzip => {
+                flate2::bufread::GzDecoder::new(&mut input).read_to_end(&mut output)?;
+            }
+            Compression::Zstd => {
+                ruzstd::decoding::StreamingDecoder::new(&mut input)
+                    .map_err(io::Error::other)?
+                    .read_to_end(&mut output)?;
+            }
+            Compression::Xz => {
+                lzma_rs::xz_decompress(&mut input, &mut output).map_err(io::Error::other)?
+            }
+            Compression::Lzma => {
+                lzma_rs::lzma_decompress(&mut input, &mut output).map_err(io::Error::other)?
+            }
+            Compression::Lz4 => lz4_legacy_decompress(&mut input, &mut output)?,
+            Compression::Bzip2 | Compression::Lzo => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{} decompression is not supported", self),
+                ))
+            }
+        }
+
+        Ok((output, data.len() - input.len()))
+    }
+}
+
+/// Decompress the legacy LZ4 format (`lz4 -l`): the magic number followed by blocks of at most
+/// 8 MiB, each prefixed with its compressed size. The format has no end marker, so the stream
+/// ends with the input or at the first zero size, i.e. padding.
+#[cfg(feature = "initrd")]
+fn lz4_legacy_decompress(input: &mut &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+    const BLOCK_SIZE: usize = 8 << 20;
+
+    while let Some(header) = input.get(..4) {
+        // Streams may be concatenated.
+        if header == LZ4_LEGACY_MAGIC {
+            *input = &input[4..];
+            continue;
+        }
+
+        let size = u32::from_le_bytes(header.try_into().expect("header is 4 bytes")) as usize;
+        if size == 0 {
+            break;
+        }
+        let block = input
+            .get(4..4 + size)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated lz4 block"))?;
+        output.extend(lz4_flex::block::decompress(block, BLOCK_SIZE).map_err(io::Error::other)?);
+        *input = &input[4 + size..];
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Compression::Gzip => "gzip",
+            Compression::Bzip2 => "bzip2",
+            Compression::Lzma => "lzma",
+            Compression::Xz => "xz",
+            Compression::Lzo => "lzo",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
+    }
+}
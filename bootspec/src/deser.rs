@@ -2,9 +2,19 @@ use std::collections::HashMap;
 use std::fmt;
 
 use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::{Serialize, Serializer};
 
+use crate::versions::{bootspec_key, specialisation_key, SUPPORTED_VERSIONS};
 use crate::Extensions;
 
+/// Whether `key` is deserialized into a `Generation`, i.e. is the bootspec or specialisation key
+/// of a supported version.
+fn is_generation_field(key: &str) -> bool {
+    SUPPORTED_VERSIONS
+        .iter()
+        .any(|&version| key == bootspec_key(version) || key == specialisation_key(version))
+}
+
 struct BootSpecExtensionsVisitor;
 
 impl<'de> Visitor<'de> for BootSpecExtensionsVisitor {
@@ -25,10 +35,10 @@ impl<'de> Visitor<'de> for BootSpecExtensionsVisitor {
             // enums (which `Generation` is). Without this, the bootspec and specialisation objects
             // would be duplicated under the `extensions` field.
             // See: https://github.com/serde-rs/serde/issues/2200
-            if ["org.nixos.bootspec.", "org.nixos.specialisation."]
-                .iter()
-                .any(|field| key.starts_with(field))
-            {
+            //
+            // Only the keys consumed by a known `Generation` are skipped. Other keys with a reserved
+            // prefix (e.g. unsupported versions) are kept, so that validation can report them.
+            if is_generation_field(&key) {
                 continue;
             }
 
@@ -53,3 +63,37 @@ where
 {
     deserializer.deserialize_map(BootSpecExtensionsVisitor)
 }
+
+/// Serialize a map with its keys sorted, so that the output does not depend on the `HashMap`'s
+/// iteration order.
+pub fn serialize_sorted<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    let mut entries = map.iter().collect::<Vec<_>>();
+    entries.sort_by_key(|(key, _)| *key);
+    serializer.collect_map(entries)
+}
+
+/// A JSON value that serializes with the keys of every object sorted, whether or not
+/// `serde_json` was built with its `preserve_order` feature.
+pub struct Sorted<'a>(pub &'a serde_json::Value);
+
+impl Serialize for Sorted<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.0 {
+            serde_json::Value::Array(values) => serializer.collect_seq(values.iter().map(Sorted)),
+            serde_json::Value::Object(map) => {
+                let mut entries = map.iter().collect::<Vec<_>>();
+                entries.sort_by_key(|(key, _)| *key);
+                serializer.collect_map(entries.into_iter().map(|(key, value)| (key, Sorted(value))))
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
@@ -4,12 +4,85 @@ use std::path::PathBuf;
 pub enum BootspecError {
     #[error("failed to synthesize: {0}")]
     Synthesize(#[from] SynthesizeError),
+    #[error("failed to assemble unified kernel image: {0}")]
+    Uki(#[from] UkiError),
+    #[error("failed to inspect kernel image: {0}")]
+    Kernel(#[from] KernelError),
+    #[error("failed to read initrd: {0}")]
+    Initrd(#[from] InitrdError),
+    #[error("failed to append initrd secrets: {0}")]
+    InitrdSecrets(#[from] InitrdSecretsError),
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error("{0} had an invalid file name")]
     InvalidFileName(PathBuf),
     #[error("{0} contained invalid UTF8")]
     InvalidUtf8(PathBuf),
+    #[error("specialisation name {0:?} may only contain ASCII letters, digits, '-' and '_'")]
+    InvalidSpecialisationName(String),
+    #[error("initrdSecrets is set, but no initrd with the secrets of {0} was given")]
+    MissingInitrdWithSecrets(PathBuf),
+    #[error("{first} and {second} would both be copied to {destination}")]
+    ConflictingBootFiles {
+        destination: PathBuf,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    #[error("failed to read {path}: {err}")]
+    ReadPath {
+        path: PathBuf,
+        #[source]
+        err: std::io::Error,
+    },
+    #[error("failed to parse {path}: {err}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        err: serde_json::Error,
+    },
+    #[error("could not find a bootspec document in {0}")]
+    MissingBootJson(PathBuf),
+    #[error("extension {key} was invalid: {err}")]
+    InvalidExtension {
+        key: String,
+        #[source]
+        err: serde_json::Error,
+    },
+    #[error("no supported bootspec version, found versions {0:?}")]
+    NoSupportedVersion(Vec<u64>),
+    #[error("no org.nixos.bootspec.vN key found")]
+    MissingVersion,
+    #[error("{pointer}: {err}")]
+    InvalidGeneration {
+        pointer: String,
+        #[source]
+        err: serde_json::Error,
+    },
+    #[error("cannot convert a v{from} generation to v{to}")]
+    UnsupportedMigration { from: u64, to: u64 },
+    #[error(
+        "converting a v{from} generation to v{to} would lose fields: {}",
+        .changes.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    LossyMigration {
+        from: u64,
+        to: u64,
+        changes: Vec<crate::migration::FieldChange>,
+    },
+    #[error("{0} names a devicetree, but has no dtbs directory to load it from")]
+    MissingDtbs(String),
+    #[error("{0}: unknown field")]
+    UnknownField(String),
+    #[error("required field {0} was not set")]
+    MissingField(&'static str),
+    #[error("extension key {0} is reserved for bootspec itself")]
+    ReservedExtensionKey(String),
+    #[error("document does not match the bootspec schema:\n{0}")]
+    Schema(crate::validation::ValidationReport),
+    #[error("failed to deserialize: {0}")]
+    Deserialize(#[source] serde_json::Error),
+    #[error("failed to serialize: {0}")]
+    Serialize(#[source] serde_json::Error),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -30,4 +103,93 @@ pub enum SynthesizeError {
     },
     #[error("could not find kernel version dir in {0}")]
     MissingKernelVersionDir(PathBuf),
+    #[error(
+        "cannot tell which of the kernel version dirs {candidates:?} in {path} belongs to the kernel (kernel image version: {image_version:?})"
+    )]
+    AmbiguousKernelVersion {
+        path: PathBuf,
+        /// The version string embedded in the kernel image, if one was found.
+        image_version: Option<String>,
+        /// The names of the kernel version dirs, sorted.
+        candidates: Vec<String>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UkiError {
+    #[error("the EFI stub is not a valid PE/COFF image: {0}")]
+    InvalidStub(&'static str),
+    #[error("the EFI stub already contains a {0} section")]
+    DuplicateSection(String),
+    #[error("section name {0} is longer than 8 bytes")]
+    SectionNameTooLong(String),
+    #[error("the EFI stub's headers have no room for {0} more sections")]
+    NoRoomForSections(usize),
+    #[error("the image would exceed the 4 GiB PE/COFF size limit")]
+    TooLarge,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KernelError {
+    #[error("not a recognised kernel image format")]
+    UnknownFormat,
+    #[error("the kernel image is malformed: {0}")]
+    Malformed(&'static str),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InitrdError {
+    #[error("unrecognised data at offset {0}")]
+    UnknownSegment(usize),
+    #[error("{compression} compressed segments (at offset {offset}) are not supported")]
+    UnsupportedCompression {
+        offset: usize,
+        compression: crate::compression::Compression,
+    },
+    #[error("failed to decompress the {compression} segment at offset {offset}: {err}")]
+    Decompress {
+        offset: usize,
+        compression: crate::compression::Compression,
+        #[source]
+        err: std::io::Error,
+    },
+    #[error("invalid cpio archive in the segment at offset {offset}: {reason}")]
+    InvalidCpio { offset: usize, reason: &'static str },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InitrdSecretsError {
+    #[error("initrdSecrets is set, but there is no initrd to append secrets to")]
+    MissingInitrd,
+    #[error("{tool} is built for {system}, which cannot run on this {host} host")]
+    IncompatibleSystem {
+        tool: PathBuf,
+        system: String,
+        host: String,
+    },
+    #[error("failed to copy {initrd} to {dir}: {err}")]
+    CopyInitrd {
+        initrd: PathBuf,
+        dir: PathBuf,
+        #[source]
+        err: std::io::Error,
+    },
+    #[error("failed to run {tool}: {err}")]
+    Spawn {
+        tool: PathBuf,
+        #[source]
+        err: std::io::Error,
+    },
+    #[error("{tool} failed with {status}: {stderr}")]
+    Failed {
+        tool: PathBuf,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+    #[error("{tool} did not finish within {timeout:?}: {stderr}")]
+    TimedOut {
+        tool: PathBuf,
+        timeout: std::time::Duration,
+        stderr: String,
+    },
 }
@@ -0,0 +1,293 @@
+//! Typed access to bootspec extensions.
+//!
+//! Extensions are arbitrary top-level keys of a bootspec document (or of a specialisation). A
+//! crate that defines an extension can describe it with a type implementing
+//! [`BootspecExtension`], so that users can read and write it without handling the raw
+//! [`serde_json::Value`]:
+//!
+//! ```
+//! use bootspec::extension::BootspecExtension;
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Splash {
+//!     image: String,
+//! }
+//!
+//! impl BootspecExtension for Splash {
+//!     const KEY: &'static str = "com.example.splash.v1";
+//! }
+//! ```
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::BootspecError;
+use crate::v1::SpecialisationV1;
+use crate::{BootJson, Extensions, Result};
+
+/// The key prefixes reserved for the bootspec document itself, e.g. `org.nixos.bootspec.v1`.
+///
+/// The rest of `org.nixos.*` is not reserved: NixOS publishes its own extensions there (e.g.
+/// [`crate::extlinux::DEVICETREE_EXTENSION`] or `org.nixos.systemd-boot`), so only the keys
+/// bootspec itself may use in some version are off limits.
+pub const RESERVED_PREFIXES: &[&str] = &["org.nixos.bootspec.", "org.nixos.specialisation."];
+
+/// A bootspec extension, stored under [`BootspecExtension::KEY`].
+pub trait BootspecExtension: Serialize + DeserializeOwned {
+    /// The key the extension is stored under, e.g. `org.nixos.devicetree.v1`. Reverse-domain
+    /// naming is recommended in order to avoid collisions.
+    const KEY: &'static str;
+}
+
+impl BootJson {
+    /// Read the extension `T`, if present.
+    ///
+    /// Returns [`BootspecError::InvalidExtension`] if it is present but cannot be deserialized
+    /// as `T`.
+    pub fn extension<T: BootspecExtension>(&self) -> Result<Option<T>> {
+        get(&self.extensions)
+    }
+
+    /// Set the extension `T`, replacing any previous value.
+    ///
+    /// Returns [`BootspecError::ReservedExtensionKey`] if [`BootspecExtension::KEY`] is reserved.
+    pub fn set_extension<T: BootspecExtension>(&mut self, extension: &T) -> Result<()> {
+        set(&mut self.extensions, extension)
+    }
+
+    /// Remove the extension `T`, returning its previous value, if any.
+    ///
+    /// The extension is removed even if its previous value cannot be deserialized as `T`.
+    pub fn remove_extension<T: BootspecExtension>(&mut self) -> Result<Option<T>> {
+        remove(&mut self.extensions)
+    }
+}
+
+impl SpecialisationV1 {
+    /// Read the extension `T` of this specialisation, if present.
+    ///
+    /// Returns [`BootspecError::InvalidExtension`] if it is present but cannot be deserialized
+    /// as `T`.
+    pub fn extension<T: BootspecExtension>(&self) -> Result<Option<T>> {
+        get(&self.extensions)
+    }
+
+    /// Set the extension `T` of this specialisation, replacing any previous value.
+    ///
+    /// Returns [`BootspecError::ReservedExtensionKey`] if [`BootspecExtension::KEY`] is reserved.
+    pub fn set_extension<T: BootspecExtension>(&mut self, extension: &T) -> Result<()> {
+        set(&mut self.extensions, extension)
+    }
+
+    /// Remove the extension `T` of this specialisation, returning its previous value, if any.
+    ///
+    /// The extension is removed even if its previous value cannot be deserialized as `T`.
+    pub fn remove_extension<T: BootspecExtension>(&mut self) -> Result<Option<T>> {
+        remove(&mut self.extensions)
+    }
+}
+
+/// Whether `key` starts with one of the [`RESERVED_PREFIXES`], and so must not be used by an
+/// extension.
+pub fn is_reserved(key: &str) -> bool {
+    RESERVED_PREFIXES
+        .iter()
+        .any(|prefix| key.starts_with(prefix))
+}
+
+/// Whether `key` follows reverse-domain naming, e.g. `com.example.splash.v1`: at least two
+/// non-empty, dot-separated components made of ASCII letters, digits, `-` and `_`, the first of
+/// which is a top-level domain made of letters only.
+pub fn is_reverse_domain(key: &str) -> bool {
+    let mut components = key.split('.');
+    let tld_valid = components
+        .next()
+        .is_some_and(|tld| !tld.is_empty() && tld.chars().all(|c| c.is_ascii_alphabetic()));
+    let mut rest = components.peekable();
+
+    tld_valid
+        && rest.peek().is_some()
+        && rest.all(|component| {
+            !component.is_empty()
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        })
+}
+
+pub(crate) fn get<T: BootspecExtension>(extensions: &Extensions) -> Result<Option<T>> {
+    extensions
+        .get(T::KEY)
+        .map(|value| deserialize(value.clone()))
+        .transpose()
+}
+
+pub(crate) fn set<T: BootspecExtension>(extensions: &mut Extensions, extension: &T) -> Result<()> {
+    if is_reserved(T::KEY) {
+        return Err(BootspecError::ReservedExtensionKey(T::KEY.to_string()));
+    }
+
+    let value = serde_json::to_value(extension).map_err(|e| invalid::<T>(e))?;
+    if value.is_null() {
+        // Documents with null extensions are rejected when deserialized.
+        return Err(invalid::<T>(serde::ser::Error::custom(
+            "null extensions are not allowed",
+        )));
+    }
+
+    extensions.insert(T::KEY.to_string(), value);
+    Ok(())
+}
+
+fn remove<T: BootspecExtension>(extensions: &mut Extensions) -> Result<Option<T>> {
+    extensions.remove(T::KEY).map(deserialize).transpose()
+}
+
+fn deserialize<T: BootspecExtension>(value: serde_json::Value) -> Result<T> {
+    serde_json::from_value(value).map_err(|e| invalid::<T>(e))
+}
+
+fn invalid<T: BootspecExtension>(err: serde_json::Error) -> BootspecError {
+    BootspecError::InvalidExtension {
+        key: T::KEY.to_string(),
+        err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::{is_reserved, is_reverse_domain, BootspecExtension};
+    use crate::error::BootspecError;
+    use crate::generation::Generation;
+    use crate::BootJson;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Splash {
+        image: String,
+    }
+
+    impl BootspecExtension for Splash {
+        const KEY: &'static str = "com.example.splash.v1";
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Nothing;
+
+    impl BootspecExtension for Nothing {
+        const KEY: &'static str = "com.example.nothing.v1";
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Impostor {}
+
+    impl BootspecExtension for Impostor {
+        const KEY: &'static str = "org.nixos.bootspec.v2";
+    }
+
+    const JSON: &str = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/xxx-nixos-system-xxx/init",
+        "kernel": "/nix/store/xxx-linux/bzImage",
+        "kernelParams": [],
+        "label": "NixOS 21.11.20210810.dirty (Linux 5.15.30)",
+        "system": "x86_64-linux",
+        "toplevel": "/nix/store/xxx-nixos-system-xxx"
+    },
+    "org.nixos.specialisation.v1": {
+        "dark": {
+            "org.nixos.bootspec.v1": {
+                "init": "/nix/store/yyy-nixos-system-yyy/init",
+                "kernel": "/nix/store/xxx-linux/bzImage",
+                "kernelParams": [],
+                "label": "NixOS 21.11.20210810.dirty (Linux 5.15.30)",
+                "system": "x86_64-linux",
+                "toplevel": "/nix/store/yyy-nixos-system-yyy"
+            },
+            "com.example.splash.v1": { "image": "dark.png" }
+        }
+    },
+    "com.example.splash.v1": { "image": "light.png" }
+}"#;
+
+    #[test]
+    fn typed_extensions() {
+        let mut boot_json: BootJson = serde_json::from_str(JSON).unwrap();
+
+        assert_eq!(
+            boot_json.extension::<Splash>().unwrap(),
+            Some(Splash {
+                image: "light.png".into()
+            })
+        );
+        assert_eq!(boot_json.extension::<Nothing>().unwrap(), None);
+
+        let Generation::V1(generation) = &mut boot_json.generation;
+        let dark = generation
+            .specialisations
+            .values_mut()
+            .next()
+            .expect("the document has a specialisation");
+        assert_eq!(
+            dark.remove_extension::<Splash>().unwrap(),
+            Some(Splash {
+                image: "dark.png".into()
+            })
+        );
+        assert!(dark.extensions.is_empty());
+        assert_eq!(dark.extension::<Splash>().unwrap(), None);
+
+        boot_json
+            .set_extension(&Splash {
+                image: "splash.png".into(),
+            })
+            .unwrap();
+        assert_eq!(
+            boot_json.extensions[Splash::KEY],
+            serde_json::json!({ "image": "splash.png" })
+        );
+    }
+
+    #[test]
+    fn errors_name_the_extension() {
+        let mut boot_json: BootJson = serde_json::from_str(JSON).unwrap();
+        boot_json
+            .extensions
+            .insert(Splash::KEY.into(), serde_json::json!({ "image": 1 }));
+
+        let err = boot_json.extension::<Splash>().unwrap_err();
+        assert!(matches!(
+            err,
+            BootspecError::InvalidExtension { ref key, .. } if key == Splash::KEY
+        ));
+        assert!(err.to_string().contains(Splash::KEY));
+
+        let err = boot_json.set_extension(&Nothing).unwrap_err();
+        assert!(matches!(
+            err,
+            BootspecError::InvalidExtension { ref key, .. } if key == Nothing::KEY
+        ));
+        assert!(!boot_json.extensions.contains_key(Nothing::KEY));
+
+        assert!(matches!(
+            boot_json.set_extension(&Impostor {}),
+            Err(BootspecError::ReservedExtensionKey(ref key)) if key == Impostor::KEY
+        ));
+    }
+
+    #[test]
+    fn key_naming() {
+        assert!(is_reserved("org.nixos.bootspec.v2"));
+        assert!(is_reserved("org.nixos.specialisation.v1"));
+        assert!(!is_reserved("org.nixos.devicetree.v1"));
+
+        assert!(is_reverse_domain("org.nixos.devicetree.v1"));
+        assert!(is_reverse_domain("com.example.my_ext-2"));
+        assert!(!is_reverse_domain("splash"));
+        assert!(!is_reverse_domain("com..example"));
+        assert!(!is_reverse_domain("com.example."));
+        assert!(!is_reverse_domain("1com.example"));
+        assert!(!is_reverse_domain("com.exa mple"));
+    }
+}
@@ -0,0 +1,405 @@
+//! Generate `extlinux.conf` files, as read by U-Boot's generic distro boot support.
+//!
+//! Kernels, initrds and devicetrees are copied into a directory next to the `extlinux` directory
+//! (`/boot/nixos` by default), named after the store paths they come from.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::boot_files::{self, BootFile, BootFiles};
+use crate::error::BootspecError;
+use crate::extension::{self, BootspecExtension};
+use crate::generation::Generation;
+use crate::v1::BootSpecV1;
+use crate::validation::resolve_in_root;
+use crate::{BootJson, Extensions, Result};
+
+/// The key of the optional devicetree extension.
+pub const DEVICETREE_EXTENSION: &str = "org.nixos.devicetree.v1";
+
+/// The devicetree extension, which overrides the devicetree a generation boots with.
+///
+/// ```json
+/// "org.nixos.devicetree.v1": {
+///   "dtbs": "/nix/store/xxx-linux-6.1/dtbs",
+///   "name": "broadcom/bcm2711-rpi-4-b.dtb"
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeviceTree {
+    /// The directory containing the compiled devicetrees (`FDTDIR`).
+    pub dtbs: Option<PathBuf>,
+    /// The devicetree to load, relative to `dtbs` (`FDT`). If unset, U-Boot picks one based on
+    /// its `fdtfile` variable.
+    pub name: Option<PathBuf>,
+}
+
+impl BootspecExtension for DeviceTree {
+    const KEY: &'static str = DEVICETREE_EXTENSION;
+}
+
+/// Settings that control the layout of the generated configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The directory files are copied to, relative to the boot partition. `extlinux.conf` itself
+    /// lives in `extlinux/`, so it refers to these files as `../$dir/...`.
+    pub dir: PathBuf,
+    /// The menu timeout, in tenths of a second.
+    pub timeout: u32,
+    /// The root directory `$toplevel/dtbs` is resolved in, e.g. the mount point of a system being
+    /// installed. The generated paths are still as seen from inside it.
+    pub root: PathBuf,
+    /// The initrds returned by [`BootSpecV1::append_initrd_secrets`], by the
+    /// [`BootSpecV1::initrd_secrets`] tool they were created with.
+    pub initrds_with_secrets: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("nixos"),
+            timeout: 50,
+            root: PathBuf::from("/"),
+            initrds_with_secrets: BTreeMap::new(),
+        }
+    }
+}
+
+/// A rendered `extlinux.conf`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extlinux {
+    /// The contents of `extlinux/extlinux.conf`.
+    pub extlinux_conf: String,
+    /// The files that must be copied to the boot partition, without duplicates. Devicetree
+    /// directories must be copied recursively.
+    pub files: Vec<BootFile>,
+}
+
+/// Render an `extlinux.conf` with a `LABEL` for each of `generations` in the order given, each
+/// followed by one `LABEL` per specialisation. The first generation is the default.
+/// Specialisation names become part of their `LABEL`, so they may only contain ASCII letters,
+/// digits, `-` and `_`.
+///
+/// Each generation is given as its generation number and its bootspec document.
+///
+/// The devicetree comes from the [`DEVICETREE_EXTENSION`] if present. If the extension has no
+/// `dtbs`, or on systems that boot with a devicetree (see [`uses_devicetree`]) without it,
+/// `$toplevel/dtbs` is used if it exists in [`Config::root`]. A devicetree `name` without any
+/// `dtbs` is an error.
+pub fn extlinux(generations: &[(u64, &BootJson)], config: &Config) -> Result<Extlinux> {
+    let mut files = BootFiles::default();
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# Generated file, all changes will be lost on nixos-rebuild!"
+    );
+    let _ = writeln!(out);
+    if let Some((number, _)) = generations.first() {
+        let _ = writeln!(out, "DEFAULT nixos-{}", number);
+    }
+    let _ = writeln!(
+        out,
+        "MENU TITLE ------------------------------------------------------------"
+    );
+    let _ = writeln!(out, "TIMEOUT {}", config.timeout);
+
+    for (number, boot_json) in generations {
+        let Generation::V1(generation) = &boot_json.generation;
+
+        write_label(
+            &mut out,
+            &format!("nixos-{}", number),
+            &format!("{} (Generation {})", generation.bootspec.label, number),
+            &generation.bootspec,
+            &boot_json.extensions,
+            config,
+            &mut files,
+        )?;
+
+        let mut specialisations = generation.specialisations.iter().collect::<Vec<_>>();
+        specialisations.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        for (name, specialisation) in specialisations {
+            let bootspec = &specialisation.generation.bootspec;
+            write_label(
+                &mut out,
+                &format!(
+                    "nixos-{}-specialisation-{}",
+                    number,
+                    boot_files::entry_name(name)?
+                ),
+                &format!("{} ({}) (Generation {})", bootspec.label, name, number),
+                bootspec,
+                &specialisation.extensions,
+                config,
+                &mut files,
+            )?;
+        }
+    }
+
+    Ok(Extlinux {
+        extlinux_conf: out,
+        files: files.0,
+    })
+}
+
+/// Whether systems of the given Nix system double, e.g. `aarch64-linux`, boot with a devicetree
+/// by default.
+pub fn uses_devicetree(system: &str) -> bool {
+    let arch = system.split('-').next().unwrap_or_default();
+    arch == "aarch64" || arch.starts_with("arm") || arch.starts_with("riscv")
+}
+
+fn write_label(
+    out: &mut String,
+    label: &str,
+    menu_label: &str,
+    bootspec: &BootSpecV1,
+    extensions: &Extensions,
+    config: &Config,
+    files: &mut BootFiles,
+) -> Result<()> {
+    let kernel = files.copy(&bootspec.kernel, &config.dir, "")?;
+    let initrd = files.copy_initrd(bootspec, &config.initrds_with_secrets, &config.dir, "")?;
+
+    let devicetree = extension::get::<DeviceTree>(extensions)?;
+    let dtbs = match devicetree
+        .as_ref()
+        .and_then(|devicetree| devicetree.dtbs.clone())
+    {
+        Some(dtbs) => Some(dtbs),
+        None if devicetree.is_some() || uses_devicetree(&bootspec.system) => {
+            toplevel_dtbs(bootspec, &config.root)?
+        }
+        None => None,
+    };
+    let name = devicetree.and_then(|devicetree| devicetree.name);
+    if name.is_some() && dtbs.is_none() {
+        return Err(BootspecError::MissingDtbs(label.to_string()));
+    }
+    let dtbs = dtbs
+        .map(|dtbs| files.copy(&dtbs, &config.dir, ""))
+        .transpose()?;
+
+    let _ = writeln!(out);
+    let _ = writeln!(out, "LABEL {}", label);
+    let _ = writeln!(out, "  MENU LABEL {}", menu_label);
+    let _ = writeln!(out, "  LINUX {}", relative(&kernel).display());
+    if let Some(initrd) = initrd {
+        let _ = writeln!(out, "  INITRD {}", relative(&initrd).display());
+    }
+    let _ = writeln!(out, "  APPEND {}", bootspec.kernel_command_line());
+    if let Some(dtbs) = dtbs {
+        match name {
+            Some(name) => {
+                let _ = writeln!(out, "  FDT {}", relative(&dtbs.join(name)).display());
+            }
+            None => {
+                let _ = writeln!(out, "  FDTDIR {}", relative(&dtbs).display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve `$toplevel/dtbs` inside `root` to the store path it links to, if it exists.
+fn toplevel_dtbs(bootspec: &BootSpecV1, root: &Path) -> Result<Option<PathBuf>> {
+    let path = bootspec.toplevel.0.join("dtbs");
+    match resolve_in_root(root, &path) {
+        Ok(dtbs) => Ok(Some(dtbs)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(BootspecError::ReadPath { path, err }),
+    }
+}
+
+/// The path of `path` (relative to the boot partition) as seen from the `extlinux` directory.
+fn relative(path: &Path) -> PathBuf {
+    Path::new("..").join(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use super::{extlinux, uses_devicetree, Config, DeviceTree, DEVICETREE_EXTENSION};
+    use crate::boot_files::BootFile;
+    use crate::error::BootspecError;
+    use crate::extension;
+    use crate::BootJson;
+
+    const JSON: &str = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/xxx-nixos-system-xxx/init",
+        "initrd": "/nix/store/xxx-initrd-linux/initrd",
+        "kernel": "/nix/store/xxx-linux/Image",
+        "kernelParams": ["console=ttyS0,115200n8"],
+        "label": "NixOS 23.05 (Linux 6.1.0)",
+        "system": "aarch64-linux",
+        "toplevel": "/nix/store/xxx-nixos-system-xxx"
+    },
+    "org.nixos.specialisation.v1": {
+        "rpi": {
+            "org.nixos.bootspec.v1": {
+                "init": "/nix/store/yyy-nixos-system-yyy/init",
+                "kernel": "/nix/store/xxx-linux/Image",
+                "kernelParams": [],
+                "label": "NixOS 23.05 (Linux 6.1.0)",
+                "system": "aarch64-linux",
+                "toplevel": "/nix/store/yyy-nixos-system-yyy"
+            },
+            "org.nixos.devicetree.v1": {
+                "dtbs": "/nix/store/zzz-device-tree-overlays/dtbs",
+                "name": "broadcom/bcm2711-rpi-4-b.dtb"
+            }
+        }
+    },
+    "org.nixos.devicetree.v1": {
+        "dtbs": "/nix/store/xxx-linux/dtbs"
+    }
+}"#;
+
+    #[test]
+    fn generation_with_devicetrees() {
+        let boot_json: BootJson = serde_json::from_str(JSON).unwrap();
+        let extlinux = extlinux(&[(3, &boot_json)], &Config::default()).unwrap();
+
+        assert_eq!(
+            extlinux.extlinux_conf,
+            "# Generated file, all changes will be lost on nixos-rebuild!
+
+DEFAULT nixos-3
+MENU TITLE ------------------------------------------------------------
+TIMEOUT 50
+
+LABEL nixos-3
+  MENU LABEL NixOS 23.05 (Linux 6.1.0) (Generation 3)
+  LINUX ../nixos/xxx-linux-Image
+  INITRD ../nixos/xxx-initrd-linux-initrd
+  APPEND init=/nix/store/xxx-nixos-system-xxx/init console=ttyS0,115200n8
+  FDTDIR ../nixos/xxx-linux-dtbs
+
+LABEL nixos-3-specialisation-rpi
+  MENU LABEL NixOS 23.05 (Linux 6.1.0) (rpi) (Generation 3)
+  LINUX ../nixos/xxx-linux-Image
+  APPEND init=/nix/store/yyy-nixos-system-yyy/init
+  FDT ../nixos/zzz-device-tree-overlays-dtbs/broadcom/bcm2711-rpi-4-b.dtb
+"
+        );
+
+        assert_eq!(
+            extlinux.files,
+            vec![
+                BootFile {
+                    source: PathBuf::from("/nix/store/xxx-linux/Image"),
+                    destination: PathBuf::from("nixos/xxx-linux-Image"),
+                },
+                BootFile {
+                    source: PathBuf::from("/nix/store/xxx-initrd-linux/initrd"),
+                    destination: PathBuf::from("nixos/xxx-initrd-linux-initrd"),
+                },
+                BootFile {
+                    source: PathBuf::from("/nix/store/xxx-linux/dtbs"),
+                    destination: PathBuf::from("nixos/xxx-linux-dtbs"),
+                },
+                BootFile {
+                    source: PathBuf::from("/nix/store/zzz-device-tree-overlays/dtbs"),
+                    destination: PathBuf::from("nixos/zzz-device-tree-overlays-dtbs"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unsafe_specialisation_names() {
+        let json = JSON.replace(r#""rpi": {"#, r#""rpi\nDEFAULT evil": {"#);
+        let boot_json: BootJson = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            extlinux(&[(1, &boot_json)], &Config::default()),
+            Err(BootspecError::InvalidSpecialisationName(name)) if name == "rpi\nDEFAULT evil"
+        ));
+    }
+
+    #[test]
+    fn invalid_devicetree_extension() {
+        let mut boot_json: BootJson = serde_json::from_str(JSON).unwrap();
+        boot_json.extensions.insert(
+            DEVICETREE_EXTENSION.into(),
+            serde_json::json!({ "dtbs": 1 }),
+        );
+
+        let err = extlinux(&[(1, &boot_json)], &Config::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            BootspecError::InvalidExtension { ref key, .. } if key == DEVICETREE_EXTENSION
+        ));
+        assert_eq!(
+            extension::get::<DeviceTree>(&Default::default()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn toplevel_dtbs_fallback() {
+        let root = TempDir::new().unwrap();
+        let store = root.path().join("nix/store");
+        fs::create_dir_all(store.join("xxx-linux/dtbs")).unwrap();
+        fs::create_dir_all(store.join("xxx-nixos-system-xxx")).unwrap();
+        symlink(
+            "/nix/store/xxx-linux/dtbs",
+            store.join("xxx-nixos-system-xxx/dtbs"),
+        )
+        .unwrap();
+        let config = Config {
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        let mut boot_json: BootJson = serde_json::from_str(JSON).unwrap();
+        boot_json.extensions.remove(DEVICETREE_EXTENSION);
+        let conf = extlinux(&[(1, &boot_json)], &config).unwrap();
+        assert!(conf
+            .extlinux_conf
+            .contains("\n  FDTDIR ../nixos/xxx-linux-dtbs\n"));
+        assert!(conf.files.contains(&BootFile {
+            source: PathBuf::from("/nix/store/xxx-linux/dtbs"),
+            destination: PathBuf::from("nixos/xxx-linux-dtbs"),
+        }));
+
+        // A name without dtbs is looked up in the same directory.
+        boot_json.extensions.insert(
+            DEVICETREE_EXTENSION.into(),
+            serde_json::json!({ "name": "broadcom/bcm2711-rpi-4-b.dtb" }),
+        );
+        let conf = extlinux(&[(1, &boot_json)], &config).unwrap();
+        assert!(conf
+            .extlinux_conf
+            .contains("\n  FDT ../nixos/xxx-linux-dtbs/broadcom/bcm2711-rpi-4-b.dtb\n"));
+
+        // ...and is an error without it.
+        fs::remove_file(store.join("xxx-nixos-system-xxx/dtbs")).unwrap();
+        let err = extlinux(&[(1, &boot_json)], &config).unwrap_err();
+        assert!(matches!(err, BootspecError::MissingDtbs(ref label) if label == "nixos-1"));
+
+        // Other errors are not mistaken for a missing directory.
+        symlink("dtbs", store.join("xxx-nixos-system-xxx/dtbs")).unwrap();
+        let err = extlinux(&[(1, &boot_json)], &config).unwrap_err();
+        assert!(matches!(err, BootspecError::ReadPath { .. }));
+    }
+
+    #[test]
+    fn devicetree_architectures() {
+        assert!(uses_devicetree("aarch64-linux"));
+        assert!(uses_devicetree("armv7l-linux"));
+        assert!(uses_devicetree("riscv64-linux"));
+        assert!(!uses_devicetree("x86_64-linux"));
+        assert!(!uses_devicetree("i686-linux"));
+    }
+}
@@ -1,24 +1,31 @@
 //! Provides a helper enum for deserializing from all available bootspec versions.
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
+use std::path::Path;
+
+use crate::error::BootspecError;
+use crate::kernel_params::KernelParams;
 use crate::v1;
+use crate::validation::escape;
+use crate::versions::{bootspec_key, specialisation_key, SUPPORTED_VERSIONS};
+use crate::{BootJson, SpecialisationName};
 
 /// An enum of all available bootspec versions.
 ///
 /// This enum is nonexhaustive, because there may be future versions added at any point, and tools
 /// should explicitly handle them (e.g. by noting they're currently unsupported).
 ///
+/// Deserialization reads the `org.nixos.bootspec.vN` keys of the document and deserializes the
+/// highest version this crate supports, so errors point at the offending field of that version.
+///
 /// ## Warnings
 ///
 /// If you attempt to deserialize using this struct, you will not get any information about
 /// user-provided extensions. For that, you must deserialize with [`crate::BootJson`].
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
 #[non_exhaustive]
 #[serde(untagged)]
 pub enum Generation {
-    // WARNING: Add new versions to the _top_ of this list. Untagged enums in `serde` always
-    // deserialize to the first variant that succeeds, and new versions should succeed before old
-    // versions.
     V1(v1::GenerationV1),
 }
 
@@ -31,14 +38,300 @@ impl Generation {
             V1(_) => v1::SCHEMA_VERSION,
         }
     }
+
+    /// Deserialize a [`Generation`] from a bootspec document, using the highest version it
+    /// contains that this crate supports.
+    ///
+    /// Unlike deserializing with [`serde`], this returns [`BootspecError::MissingVersion`] and
+    /// [`BootspecError::NoSupportedVersion`] when the document has no usable version, and
+    /// [`BootspecError::InvalidGeneration`] with a JSON pointer to the offending field otherwise.
+    pub fn from_value(document: &serde_json::Value) -> Result<Self, BootspecError> {
+        Self::from_value_with(document, UnknownFields::Drop)
+    }
+
+    /// Like [`Generation::from_value`], treating unknown fields inside the bootspec object as
+    /// `unknown_fields` says.
+    pub fn from_value_with(
+        document: &serde_json::Value,
+        unknown_fields: UnknownFields,
+    ) -> Result<Self, BootspecError> {
+        let Some(fields) = document.as_object() else {
+            return Err(BootspecError::MissingVersion);
+        };
+
+        let version = Self::select_version(fields)?;
+        let mut generation = Self::from_fields(version, fields)?;
+        match &mut generation {
+            Generation::V1(generation) => generation.unknown_fields(fields, "", unknown_fields)?,
+        }
+
+        Ok(generation)
+    }
+
+    /// The highest supported version among the `org.nixos.bootspec.vN` keys of `fields`.
+    pub(crate) fn select_version(
+        fields: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<u64, BootspecError> {
+        let mut found = fields
+            .keys()
+            .filter_map(|key| key.strip_prefix("org.nixos.bootspec.v")?.parse().ok())
+            .collect::<Vec<u64>>();
+        found.sort_unstable();
+
+        match found.iter().rev().find(|v| SUPPORTED_VERSIONS.contains(v)) {
+            Some(&version) => Ok(version),
+            None if found.is_empty() => Err(BootspecError::MissingVersion),
+            None => Err(BootspecError::NoSupportedVersion(found)),
+        }
+    }
+
+    /// Deserialize the generation of `version` from the top-level `fields` of a document.
+    pub(crate) fn from_fields(
+        version: u64,
+        fields: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Self, BootspecError> {
+        let mut generation = serde_json::Map::new();
+        for key in [bootspec_key(version), specialisation_key(version)] {
+            if let Some(value) = fields.get(&key) {
+                generation.insert(key, value.clone());
+            }
+        }
+        let generation = serde_json::Value::Object(generation);
+
+        match version {
+            v1::SCHEMA_VERSION => deserialize_at_path(generation).map(Generation::V1),
+            v => Err(BootspecError::NoSupportedVersion(vec![v])),
+        }
+    }
+}
+
+/// Deserialize `value`, reporting errors with a JSON pointer to the offending field.
+fn deserialize_at_path<T: serde::de::DeserializeOwned>(
+    value: serde_json::Value,
+) -> Result<T, BootspecError> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let mut pointer = String::new();
+        for segment in e.path().iter() {
+            pointer.push('/');
+            match segment {
+                serde_path_to_error::Segment::Seq { index } => pointer.push_str(&index.to_string()),
+                serde_path_to_error::Segment::Map { key } => pointer.push_str(&escape(key)),
+                serde_path_to_error::Segment::Enum { variant } => {
+                    pointer.push_str(&escape(variant))
+                }
+                serde_path_to_error::Segment::Unknown => pointer.push('?'),
+            }
+        }
+
+        BootspecError::InvalidGeneration {
+            pointer,
+            err: e.into_inner(),
+        }
+    })
+}
+
+impl<'de> Deserialize<'de> for Generation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields = serde_json::Map::deserialize(deserializer)?;
+        let version = Generation::select_version(&fields).map_err(serde::de::Error::custom)?;
+
+        Generation::from_fields(version, &fields).map_err(serde::de::Error::custom)
+    }
+}
+
+/// How to treat fields inside a version's bootspec object (e.g. `org.nixos.bootspec.v1`) that
+/// this crate does not know.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownFields {
+    /// Ignore them. This is what deserializing with [`serde`] does.
+    #[default]
+    Drop,
+    /// Keep them (e.g. in [`v1::BootSpecV1::unknown_fields`]), so that they are written back out.
+    Preserve,
+    /// Fail with [`BootspecError::UnknownField`].
+    Deny,
+}
+
+/// Read access to a generation that works the same for every bootspec version.
+///
+/// This is implemented by [`Generation`] (and [`BootJson`]) as well as by each version's
+/// generation type, so that code reading a generation does not need to match on [`Generation`].
+pub trait BootspecGeneration {
+    /// A human-readable label for the system.
+    fn label(&self) -> &str;
+    /// The kernel image.
+    fn kernel(&self) -> &Path;
+    /// The kernel parameters, without `init=`.
+    fn kernel_params(&self) -> &KernelParams;
+    /// The stage-2 init.
+    fn init(&self) -> &Path;
+    /// The initrd, if any.
+    fn initrd(&self) -> Option<&Path>;
+    /// The tool that appends secrets to the initrd, if any.
+    fn initrd_secrets(&self) -> Option<&Path>;
+    /// The Nix system double, e.g. `x86_64-linux`.
+    fn system(&self) -> &str;
+    /// The top-level store path of the system closure.
+    fn toplevel(&self) -> &Path;
+    /// The specialisations, sorted by name.
+    fn specialisations(&self) -> Vec<(&SpecialisationName, &dyn BootspecGeneration)>;
+}
+
+impl BootspecGeneration for v1::GenerationV1 {
+    fn label(&self) -> &str {
+        &self.bootspec.label
+    }
+
+    fn kernel(&self) -> &Path {
+        &self.bootspec.kernel
+    }
+
+    fn kernel_params(&self) -> &KernelParams {
+        &self.bootspec.kernel_params
+    }
+
+    fn init(&self) -> &Path {
+        &self.bootspec.init
+    }
+
+    fn initrd(&self) -> Option<&Path> {
+        self.bootspec.initrd.as_deref()
+    }
+
+    fn initrd_secrets(&self) -> Option<&Path> {
+        self.bootspec.initrd_secrets.as_deref()
+    }
+
+    fn system(&self) -> &str {
+        &self.bootspec.system
+    }
+
+    fn toplevel(&self) -> &Path {
+        &self.bootspec.toplevel.0
+    }
+
+    fn specialisations(&self) -> Vec<(&SpecialisationName, &dyn BootspecGeneration)> {
+        let mut specialisations = self
+            .specialisations
+            .iter()
+            .map(|(name, specialisation)| {
+                (name, &specialisation.generation as &dyn BootspecGeneration)
+            })
+            .collect::<Vec<_>>();
+        specialisations.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        specialisations
+    }
+}
+
+impl Generation {
+    fn inner(&self) -> &dyn BootspecGeneration {
+        match self {
+            Generation::V1(generation) => generation,
+        }
+    }
+}
+
+impl BootspecGeneration for Generation {
+    fn label(&self) -> &str {
+        self.inner().label()
+    }
+
+    fn kernel(&self) -> &Path {
+        self.inner().kernel()
+    }
+
+    fn kernel_params(&self) -> &KernelParams {
+        self.inner().kernel_params()
+    }
+
+    fn init(&self) -> &Path {
+        self.inner().init()
+    }
+
+    fn initrd(&self) -> Option<&Path> {
+        self.inner().initrd()
+    }
+
+    fn initrd_secrets(&self) -> Option<&Path> {
+        self.inner().initrd_secrets()
+    }
+
+    fn system(&self) -> &str {
+        self.inner().system()
+    }
+
+    fn toplevel(&self) -> &Path {
+        self.inner().toplevel()
+    }
+
+    fn specialisations(&self) -> Vec<(&SpecialisationName, &dyn BootspecGeneration)> {
+        self.inner().specialisations()
+    }
+}
+
+impl BootspecGeneration for BootJson {
+    fn label(&self) -> &str {
+        self.generation.label()
+    }
+
+    fn kernel(&self) -> &Path {
+        self.generation.kernel()
+    }
+
+    fn kernel_params(&self) -> &KernelParams {
+        self.generation.kernel_params()
+    }
+
+    fn init(&self) -> &Path {
+        self.generation.init()
+    }
+
+    fn initrd(&self) -> Option<&Path> {
+        self.generation.initrd()
+    }
+
+    fn initrd_secrets(&self) -> Option<&Path> {
+        self.generation.initrd_secrets()
+    }
+
+    fn system(&self) -> &str {
+        self.generation.system()
+    }
+
+    fn toplevel(&self) -> &Path {
+        self.generation.toplevel()
+    }
+
+    fn specialisations(&self) -> Vec<(&SpecialisationName, &dyn BootspecGeneration)> {
+        self.generation.specialisations()
+    }
+}
+
+impl From<v1::GenerationV1> for Generation {
+    fn from(generation: v1::GenerationV1) -> Self {
+        Generation::V1(generation)
+    }
 }
 
 impl TryFrom<Generation> for v1::GenerationV1 {
     type Error = crate::BootspecError;
 
+    /// Convert to a [`v1::GenerationV1`], failing if that would lose any fields.
+    ///
+    /// Use [`Generation::downgrade_to`] to accept a lossy conversion.
     fn try_from(value: Generation) -> Result<Self, Self::Error> {
+        let from = value.version();
+        let migrated = value.downgrade_to(v1::SCHEMA_VERSION)?;
+        if !migrated.is_lossless() {
+            return Err(crate::BootspecError::LossyMigration {
+                from,
+                to: v1::SCHEMA_VERSION,
+                changes: migrated.changes,
+            });
+        }
+
         #[allow(clippy::infallible_destructuring_match)]
-        let ret = match value {
+        let ret = match migrated.generation {
             Generation::V1(v1) => v1,
         };
 
@@ -49,12 +342,13 @@ impl TryFrom<Generation> for v1::GenerationV1 {
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
 
     use serde::de::IntoDeserializer;
     use serde::{Deserialize, Serialize};
 
-    use super::Generation;
+    use super::{BootspecGeneration, Generation};
+    use crate::error::BootspecError;
     use crate::{
         v1::{BootSpecV1, GenerationV1},
         BootJson, SpecialisationName, SystemConfigurationRoot, SCHEMA_VERSION,
@@ -66,12 +360,6 @@ mod tests {
         test: String,
     }
 
-    #[derive(Debug, Deserialize, Serialize, PartialEq, Default)]
-    struct TestOptionalExtension {
-        #[serde(rename = "key")]
-        test: Option<String>,
-    }
-
     #[test]
     fn valid_v1_rfc0125_json() {
         // Adapted from the official JSON5 document from the RFC (converted to JSON and modified to
@@ -157,6 +445,7 @@ mod tests {
                 "/nix/store/xxx-append-secrets/bin/append-initrd-secrets",
             )),
             toplevel: SystemConfigurationRoot(PathBuf::from("/nix/store/xxx-nixos-system-xxx")),
+            unknown_fields: Default::default(),
         };
         let expected = GenerationV1 {
             bootspec,
@@ -217,6 +506,7 @@ mod tests {
                 "/nix/store/xxx-append-secrets/bin/append-initrd-secrets",
             )),
             toplevel: SystemConfigurationRoot(PathBuf::from("/nix/store/xxx-nixos-system-xxx")),
+            unknown_fields: Default::default(),
         };
         let generation = GenerationV1 {
             bootspec,
@@ -294,6 +584,7 @@ mod tests {
                 "/nix/store/xxx-append-secrets/bin/append-initrd-secrets",
             )),
             toplevel: SystemConfigurationRoot(PathBuf::from("/nix/store/xxx-nixos-system-xxx")),
+            unknown_fields: Default::default(),
         };
         let generation = GenerationV1 {
             bootspec,
@@ -389,6 +680,7 @@ mod tests {
                 "/nix/store/xxx-append-secrets/bin/append-initrd-secrets",
             )),
             toplevel: SystemConfigurationRoot(PathBuf::from("/nix/store/xxx-nixos-system-xxx")),
+            unknown_fields: Default::default(),
         };
         let generation = GenerationV1 {
             bootspec,
@@ -447,6 +739,7 @@ mod tests {
             initrd: None,
             initrd_secrets: None,
             toplevel: SystemConfigurationRoot(PathBuf::from("/nix/store/xxx-nixos-system-xxx")),
+            unknown_fields: Default::default(),
         };
         let generation = GenerationV1 {
             bootspec,
@@ -519,7 +812,58 @@ mod tests {
         );
 
         let json_err = serde_json::from_str::<Generation>(&json).unwrap_err();
-        assert!(json_err.to_string().contains("did not match any variant"));
+        assert!(json_err
+            .to_string()
+            .contains("no supported bootspec version"));
+
+        let err = Generation::from_value(&serde_json::from_str(&json).unwrap()).unwrap_err();
+        assert!(matches!(
+            err,
+            BootspecError::NoSupportedVersion(ref found) if *found == vec![SCHEMA_VERSION + 1]
+        ));
+    }
+
+    #[test]
+    fn invalid_v1_json_reports_path() {
+        let json = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/xxx-nixos-system-xxx/init",
+        "kernel": "/nix/store/xxx-linux/bzImage",
+        "kernelParams": ["loglevel=4", 4],
+        "label": "NixOS 21.11.20210810.dirty (Linux 5.15.30)",
+        "system": "x86_64-linux",
+        "toplevel": "/nix/store/xxx-nixos-system-xxx"
+    },
+    "org.test": { "key": "hello" }
+}"#;
+
+        let json_err = serde_json::from_str::<BootJson>(json).unwrap_err();
+        assert!(
+            json_err
+                .to_string()
+                .starts_with("/org.nixos.bootspec.v1/kernelParams/1: invalid type: integer `4`"),
+            "{}",
+            json_err
+        );
+
+        let err = Generation::from_value(&serde_json::from_str(json).unwrap()).unwrap_err();
+        assert!(matches!(
+            err,
+            BootspecError::InvalidGeneration { ref pointer, .. }
+                if pointer == "/org.nixos.bootspec.v1/kernelParams/1"
+        ));
+
+        let json = json
+            .replace(r#""kernel": "/nix/store/xxx-linux/bzImage","#, "")
+            .replace("4]", "\"quiet\"]");
+        let err = Generation::from_value(&serde_json::from_str(&json).unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "/org.nixos.bootspec.v1: missing field `kernel`"
+        );
+
+        let err = Generation::from_value(&serde_json::json!({})).unwrap_err();
+        assert!(matches!(err, BootspecError::MissingVersion));
     }
 
     #[test]
@@ -550,4 +894,42 @@ mod tests {
         let from_json: BootJson = serde_json::from_str(json).unwrap();
         let _generation: GenerationV1 = from_json.generation.try_into().unwrap();
     }
+
+    #[test]
+    fn version_agnostic_access() {
+        let boot_json: BootJson =
+            serde_json::from_str(include_str!("../rfc0125_spec.json")).unwrap();
+
+        let generation: &dyn BootspecGeneration = &boot_json;
+        assert_eq!(
+            generation.label(),
+            "NixOS 21.11.20210810.dirty (Linux 5.15.30)"
+        );
+        assert_eq!(
+            generation.kernel(),
+            Path::new("/nix/store/xxx-linux/bzImage")
+        );
+        assert_eq!(generation.kernel_params().value("amd_iommu"), Some("pt"));
+        assert_eq!(
+            generation.init(),
+            Path::new("/nix/store/xxx-nixos-system-xxx/init")
+        );
+        assert_eq!(
+            generation.initrd(),
+            Some(Path::new("/nix/store/xxx-initrd-linux/initrd"))
+        );
+        assert!(generation.initrd_secrets().is_some());
+        assert_eq!(generation.system(), "x86_64-linux");
+        assert_eq!(
+            generation.toplevel(),
+            Path::new("/nix/store/xxx-nixos-system-xxx")
+        );
+
+        let specialisations = boot_json.generation.specialisations();
+        assert_eq!(specialisations.len(), 1);
+        let (name, specialisation) = specialisations[0];
+        assert_eq!(name.0, "<name>");
+        assert_eq!(specialisation.system(), "x86_64-linux");
+        assert!(specialisation.specialisations().is_empty());
+    }
 }
@@ -0,0 +1,360 @@
+//! Generate GRUB 2 menu entries.
+//!
+//! The generated fragment is meant to be included in (or appended to) a `grub.cfg`.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::boot_files::{self, BootFile, BootFiles};
+use crate::generation::Generation;
+use crate::v1::BootSpecV1;
+use crate::{BootJson, Result};
+
+/// The GRUB variable the boot partition's device is stored in when [`Config::search`] is set.
+const DRIVE_VARIABLE: &str = "drive1";
+
+/// How GRUB should find the partition holding the kernels and initrds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Search {
+    /// Search by filesystem UUID (`search --fs-uuid`).
+    Uuid(String),
+    /// Search by filesystem label (`search --label`).
+    Label(String),
+}
+
+/// Where GRUB reads kernels and initrds from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Files {
+    /// Read the files directly from the Nix store on the searched partition. Suitable when
+    /// `/boot` is not a separate partition.
+    Store,
+    /// Copy the files into `dir` on a separate `/boot` partition. `dir` is relative to the root of
+    /// that partition, e.g. `/kernels`.
+    Copied { dir: PathBuf },
+}
+
+/// Settings that control how the menu finds its files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// How to find the partition holding the files. If unset, GRUB's `$root` is used.
+    pub search: Option<Search>,
+    /// Where the files are read from.
+    pub files: Files,
+    /// The initrds returned by [`BootSpecV1::append_initrd_secrets`], by the
+    /// [`BootSpecV1::initrd_secrets`] tool they were created with.
+    pub initrds_with_secrets: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            search: None,
+            files: Files::Copied {
+                dir: PathBuf::from("/kernels"),
+            },
+            initrds_with_secrets: BTreeMap::new(),
+        }
+    }
+}
+
+/// A rendered GRUB menu.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Menu {
+    /// The `grub.cfg` fragment.
+    pub grub_cfg: String,
+    /// The kernels and initrds that must be copied to the boot partition, without duplicates.
+    /// Empty when using [`Files::Store`].
+    pub files: Vec<BootFile>,
+}
+
+/// Render a menu entry for each of `generations` in the order given, each followed by a submenu
+/// listing its `org.nixos.specialisation.v1` entries (if any).
+///
+/// Each generation is given as its generation number and its bootspec document.
+pub fn menu(generations: &[(u64, &BootJson)], config: &Config) -> Result<Menu> {
+    let mut files = BootFiles::default();
+    let mut out = String::new();
+
+    match &config.search {
+        Some(Search::Uuid(uuid)) => {
+            let _ = writeln!(
+                out,
+                "search --set={} --fs-uuid {}",
+                DRIVE_VARIABLE,
+                quote(uuid)
+            );
+        }
+        Some(Search::Label(label)) => {
+            let _ = writeln!(
+                out,
+                "search --set={} --label {}",
+                DRIVE_VARIABLE,
+                quote(label)
+            );
+        }
+        None => {}
+    }
+
+    for (number, boot_json) in generations {
+        let Generation::V1(generation) = &boot_json.generation;
+        let title = format!("{} (Generation {})", generation.bootspec.label, number);
+
+        write_entry(
+            &mut out,
+            "",
+            &title,
+            &generation.bootspec,
+            config,
+            &mut files,
+        )?;
+
+        if generation.specialisations.is_empty() {
+            continue;
+        }
+
+        let mut specialisations = generation.specialisations.iter().collect::<Vec<_>>();
+        specialisations.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+
+        let _ = writeln!(
+            out,
+            "submenu {} {{",
+            quote(&format!("{} - Specialisations", title))
+        );
+        for (name, specialisation) in specialisations {
+            let bootspec = &specialisation.generation.bootspec;
+            let title = format!("{} ({}) (Generation {})", bootspec.label, name, number);
+            write_entry(&mut out, "  ", &title, bootspec, config, &mut files)?;
+        }
+        let _ = writeln!(out, "}}");
+    }
+
+    Ok(Menu {
+        grub_cfg: out,
+        files: files.0,
+    })
+}
+
+fn write_entry(
+    out: &mut String,
+    indent: &str,
+    title: &str,
+    bootspec: &BootSpecV1,
+    config: &Config,
+    files: &mut BootFiles,
+) -> Result<()> {
+    let kernel = match &config.files {
+        Files::Store => bootspec.kernel.clone(),
+        Files::Copied { dir } => files.copy(&bootspec.kernel, dir, "")?,
+    };
+    let initrd = match &config.files {
+        Files::Store => {
+            boot_files::initrd(bootspec, &config.initrds_with_secrets)?.map(Path::to_path_buf)
+        }
+        Files::Copied { dir } => {
+            files.copy_initrd(bootspec, &config.initrds_with_secrets, dir, "")?
+        }
+    };
+    let kernel = grub_path(&kernel, config);
+    let initrd = initrd.map(|initrd| grub_path(&initrd, config));
+
+    let _ = writeln!(
+        out,
+        "{}menuentry {} --class nixos --unrestricted {{",
+        indent,
+        quote(title)
+    );
+    // GRUB strips double quotes and expands variables in unquoted words, so each parameter is
+    // quoted to reach the kernel unchanged.
+    let cmdline = std::iter::once(format!("init={}", bootspec.init.display()))
+        .chain(bootspec.kernel_params.iter().map(ToString::to_string))
+        .map(|param| quote(&param))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let _ = writeln!(out, "{}  linux {} {}", indent, kernel, cmdline);
+    if let Some(initrd) = initrd {
+        let _ = writeln!(out, "{}  initrd {}", indent, initrd);
+    }
+    let _ = writeln!(out, "{}}}", indent);
+
+    Ok(())
+}
+
+/// The path GRUB should load `path` on the boot partition from.
+fn grub_path(path: &Path, config: &Config) -> String {
+    let device = match config.search {
+        Some(_) => format!("(${})", DRIVE_VARIABLE),
+        None => String::new(),
+    };
+
+    format!("{}{}", device, path.display())
+}
+
+/// Quote `s` as a single GRUB word. Inside single quotes GRUB performs no expansion, and a
+/// literal `'` must be written as `'\''`.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{menu, quote, Config, Files, Search};
+    use crate::boot_files::BootFile;
+    use crate::error::BootspecError;
+    use crate::BootJson;
+
+    const JSON: &str = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/xxx-nixos-system-xxx/init",
+        "initrd": "/nix/store/xxx-initrd-linux/initrd",
+        "kernel": "/nix/store/xxx-linux/bzImage",
+        "kernelParams": ["loglevel=4"],
+        "label": "NixOS 21.11 (Linux 5.15.30)",
+        "system": "x86_64-linux",
+        "toplevel": "/nix/store/xxx-nixos-system-xxx"
+    },
+    "org.nixos.specialisation.v1": {
+        "no-gui": {
+            "org.nixos.bootspec.v1": {
+                "init": "/nix/store/yyy-nixos-system-yyy/init",
+                "initrd": "/nix/store/xxx-initrd-linux/initrd",
+                "kernel": "/nix/store/xxx-linux/bzImage",
+                "kernelParams": ["loglevel=4", "quiet"],
+                "label": "NixOS 21.11 (Linux 5.15.30)",
+                "system": "x86_64-linux",
+                "toplevel": "/nix/store/yyy-nixos-system-yyy"
+            }
+        }
+    }
+}"#;
+
+    const JSON_NO_SPECIALISATIONS: &str = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/zzz-nixos-system-zzz/init",
+        "kernel": "/nix/store/zzz-linux/bzImage",
+        "kernelParams": [],
+        "label": "NixOS 21.05 (Linux 5.10.1)",
+        "system": "x86_64-linux",
+        "toplevel": "/nix/store/zzz-nixos-system-zzz"
+    }
+}"#;
+
+    #[test]
+    fn separate_boot_partition() {
+        let latest: BootJson = serde_json::from_str(JSON).unwrap();
+        let older: BootJson = serde_json::from_str(JSON_NO_SPECIALISATIONS).unwrap();
+        let config = Config {
+            search: Some(Search::Uuid("1234-ABCD".into())),
+            ..Default::default()
+        };
+
+        let menu = menu(&[(2, &latest), (1, &older)], &config).unwrap();
+
+        assert_eq!(
+            menu.grub_cfg,
+            r"search --set=drive1 --fs-uuid '1234-ABCD'
+menuentry 'NixOS 21.11 (Linux 5.15.30) (Generation 2)' --class nixos --unrestricted {
+  linux ($drive1)/kernels/xxx-linux-bzImage 'init=/nix/store/xxx-nixos-system-xxx/init' 'loglevel=4'
+  initrd ($drive1)/kernels/xxx-initrd-linux-initrd
+}
+submenu 'NixOS 21.11 (Linux 5.15.30) (Generation 2) - Specialisations' {
+  menuentry 'NixOS 21.11 (Linux 5.15.30) (no-gui) (Generation 2)' --class nixos --unrestricted {
+    linux ($drive1)/kernels/xxx-linux-bzImage 'init=/nix/store/yyy-nixos-system-yyy/init' 'loglevel=4' 'quiet'
+    initrd ($drive1)/kernels/xxx-initrd-linux-initrd
+  }
+}
+menuentry 'NixOS 21.05 (Linux 5.10.1) (Generation 1)' --class nixos --unrestricted {
+  linux ($drive1)/kernels/zzz-linux-bzImage 'init=/nix/store/zzz-nixos-system-zzz/init'
+}
+"
+        );
+
+        assert_eq!(
+            menu.files,
+            vec![
+                BootFile {
+                    source: PathBuf::from("/nix/store/xxx-linux/bzImage"),
+                    destination: PathBuf::from("/kernels/xxx-linux-bzImage"),
+                },
+                BootFile {
+                    source: PathBuf::from("/nix/store/xxx-initrd-linux/initrd"),
+                    destination: PathBuf::from("/kernels/xxx-initrd-linux-initrd"),
+                },
+                BootFile {
+                    source: PathBuf::from("/nix/store/zzz-linux/bzImage"),
+                    destination: PathBuf::from("/kernels/zzz-linux-bzImage"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn store_on_labelled_partition() {
+        let older: BootJson = serde_json::from_str(JSON_NO_SPECIALISATIONS).unwrap();
+        let config = Config {
+            search: Some(Search::Label("nixos".into())),
+            files: Files::Store,
+            ..Default::default()
+        };
+
+        let menu = menu(&[(1, &older)], &config).unwrap();
+
+        assert_eq!(
+            menu.grub_cfg,
+            r"search --set=drive1 --label 'nixos'
+menuentry 'NixOS 21.05 (Linux 5.10.1) (Generation 1)' --class nixos --unrestricted {
+  linux ($drive1)/nix/store/zzz-linux/bzImage 'init=/nix/store/zzz-nixos-system-zzz/init'
+}
+"
+        );
+        assert!(menu.files.is_empty());
+    }
+
+    #[test]
+    fn initrd_with_secrets() {
+        let mut boot_json: BootJson = serde_json::from_str(JSON_NO_SPECIALISATIONS).unwrap();
+        let crate::generation::Generation::V1(generation) = &mut boot_json.generation;
+        let tool = PathBuf::from("/nix/store/zzz-nixos-system-zzz/append-initrd-secrets");
+        generation.bootspec.initrd = Some(PathBuf::from("/nix/store/zzz-initrd-linux/initrd"));
+        generation.bootspec.initrd_secrets = Some(tool.clone());
+
+        assert!(matches!(
+            menu(&[(1, &boot_json)], &Config::default()),
+            Err(BootspecError::MissingInitrdWithSecrets(missing)) if missing == tool
+        ));
+
+        let mut config = Config {
+            files: Files::Store,
+            ..Default::default()
+        };
+        config
+            .initrds_with_secrets
+            .insert(tool, PathBuf::from("/boot/initrd-secrets-1234"));
+        let menu = menu(&[(1, &boot_json)], &config).unwrap();
+        assert_eq!(
+            menu.grub_cfg.lines().nth(2).unwrap(),
+            "  initrd /boot/initrd-secrets-1234"
+        );
+    }
+
+    #[test]
+    fn kernel_params_are_quoted() {
+        let mut boot_json: BootJson = serde_json::from_str(JSON_NO_SPECIALISATIONS).unwrap();
+        let crate::generation::Generation::V1(generation) = &mut boot_json.generation;
+        generation.bootspec.kernel_params = vec!["foo=\"a b\"", "root=$root", "it's"].into();
+
+        let menu = menu(&[(1, &boot_json)], &Config::default()).unwrap();
+
+        assert_eq!(
+            menu.grub_cfg.lines().nth(1).unwrap(),
+            r#"  linux /kernels/zzz-linux-bzImage 'init=/nix/store/zzz-nixos-system-zzz/init' 'foo="a b"' 'root=$root' 'it'\''s'"#
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("plain"), "'plain'");
+        assert_eq!(quote("it's $here"), r"'it'\''s $here'");
+    }
+}
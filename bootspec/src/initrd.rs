@@ -0,0 +1,471 @@
+//! Inspect initrds without booting them.
+//!
+//! An initrd is a sequence of segments, each of which is a `newc` cpio archive that is either
+//! uncompressed (e.g. early microcode) or compressed as a whole. The kernel unpacks the segments
+//! in order, so a file in a later segment replaces one with the same name in an earlier segment.
+//!
+//! Requires the `initrd` feature.
+use std::fs;
+use std::path::Path;
+
+use crate::compression::Compression;
+use crate::error::{BootspecError, InitrdError};
+use crate::v1::BootSpecV1;
+use crate::Result;
+
+/// The size of a `newc` cpio header.
+const HEADER_SIZE: usize = 110;
+/// The name of the entry that ends a cpio archive.
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// An initrd, read into memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Initrd {
+    data: Vec<u8>,
+}
+
+/// A segment of an [`Initrd`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The offset of the segment in the initrd.
+    pub offset: usize,
+    /// How the segment is compressed, or `None` if it is not.
+    pub compression: Option<Compression>,
+    /// The entries of the segment's archive(s), in order.
+    pub entries: Vec<Entry>,
+}
+
+/// An entry of a cpio archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The path of the entry, relative to the root and without a leading `./`, e.g.
+    /// `lib/modules`.
+    pub name: String,
+    /// What kind of file the entry is.
+    pub kind: EntryKind,
+    /// The permission bits, e.g. `0o755`.
+    pub permissions: u32,
+    /// The size of the entry's data.
+    pub size: usize,
+}
+
+/// The kinds of [`Entry`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink {
+        /// The target of the symlink.
+        target: String,
+    },
+    /// A device node, FIFO or socket.
+    Other,
+}
+
+impl BootSpecV1 {
+    /// Read the initrd referenced by [`BootSpecV1::initrd`], if there is one.
+    pub fn open_initrd(&self) -> Result<Option<Initrd>> {
+        self.initrd.as_deref().map(Initrd::read).transpose()
+    }
+}
+
+impl Initrd {
+    /// Read the initrd at `path`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let data = fs::read(path).map_err(|e| BootspecError::ReadPath {
+            path: path.to_path_buf(),
+            err: e,
+        })?;
+        Ok(Self::from_bytes(data))
+    }
+
+    /// An initrd with the contents `data`.
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    /// The segments of the initrd, decompressing them as needed.
+    pub fn segments(&self) -> Result<Vec<Segment>, InitrdError> {
+        self.walk(|_, _| {})
+    }
+
+    /// The contents of the regular file `name` (e.g. `etc/modprobe.d/nixos.conf`), as the kernel
+    /// would unpack it, or `None` if there is no such file.
+    pub fn extract(&self, name: &str) -> Result<Option<Vec<u8>>, InitrdError> {
+        let name = normalize(name);
+        let mut contents = None;
+        self.walk(|entry, data| {
+            if entry.name == name {
+                contents = (entry.kind == EntryKind::File).then(|| data.to_vec());
+            }
+        })?;
+        Ok(contents)
+    }
+
+    /// The kernel versions the initrd has modules for, i.e. the names of the directories
+    /// `lib/modules/*` anywhere in the initrd, sorted.
+    ///
+    /// Directories in the Nix store are included, since stage 1 usually links `lib` to a store
+    /// path inside the initrd.
+    pub fn kernel_module_versions(&self) -> Result<Vec<String>, InitrdError> {
+        let mut versions = self
+            .segments()?
+            .into_iter()
+            .flat_map(|segment| segment.entries)
+            .filter(|entry| entry.kind == EntryKind::Directory)
+            .filter_map(|entry| {
+                let (parent, version) = entry.name.rsplit_once('/')?;
+                (parent == "lib/modules" || parent.ends_with("/lib/modules"))
+                    .then(|| version.to_string())
+            })
+            .collect::<Vec<_>>();
+        versions.sort();
+        versions.dedup();
+        Ok(versions)
+    }
+
+    /// Parse every segment, calling `visit` with each entry and its data.
+    fn walk(&self, mut visit: impl FnMut(&Entry, &[u8])) -> Result<Vec<Segment>, InitrdError> {
+        let mut segments = Vec::new();
+        let mut offset = 0;
+
+        while offset < self.data.len() {
+            let rest = &self.data[offset..];
+            // Segments are padded with zeros.
+            if rest[0] == 0 {
+                offset += 1;
+                continue;
+            }
+
+            let (compression, consumed, entries) = if rest.starts_with(b"0707") {
+                let (consumed, entries) = parse_cpio(rest, offset, &mut visit)?;
+                (None, consumed, entries)
+            } else {
+                let compression =
+                    Compression::detect(rest).ok_or(InitrdError::UnknownSegment(offset))?;
+                let (archive, consumed) = compression.decompress(rest).map_err(|err| {
+                    if err.kind() == std::io::ErrorKind::Unsupported {
+                        InitrdError::UnsupportedCompression {
+                            offset,
+                            compression,
+                        }
+                    } else {
+                        InitrdError::Decompress {
+                            offset,
+                            compression,
+                            err,
+                        }
+                    }
+                })?;
+                let entries = parse_archives(&archive, offset, &mut visit)?;
+                (Some(compression), consumed, entries)
+            };
+
+            segments.push(Segment {
+                offset,
+                compression,
+                entries,
+            });
+            offset += consumed.max(1);
+        }
+
+        Ok(segments)
+    }
+}
+
+/// Parse the decompressed contents of a segment, which may be several archives separated by
+/// padding.
+fn parse_archives(
+    mut data: &[u8],
+    offset: usize,
+    visit: &mut impl FnMut(&Entry, &[u8]),
+) -> Result<Vec<Entry>, InitrdError> {
+    let mut entries = Vec::new();
+    loop {
+        data = &data[data.iter().take_while(|&&b| b == 0).count()..];
+        if data.is_empty() {
+            return Ok(entries);
+        }
+
+        let (consumed, mut archive) = parse_cpio(data, offset, visit)?;
+        entries.append(&mut archive);
+        data = &data[consumed..];
+    }
+}
+
+/// Parse a single archive at the start of `data`, up to and including its trailer. Returns the
+/// number of bytes it took up and its entries, without the trailer.
+fn parse_cpio(
+    data: &[u8],
+    offset: usize,
+    visit: &mut impl FnMut(&Entry, &[u8]),
+) -> Result<(usize, Vec<Entry>), InitrdError> {
+    let invalid = |reason| InitrdError::InvalidCpio { offset, reason };
+
+    let mut entries = Vec::new();
+    let mut position = 0;
+    loop {
+        let header = data
+            .get(position..position + HEADER_SIZE)
+            .ok_or(invalid("truncated header"))?;
+        if &header[..6] != b"070701" && &header[..6] != b"070702" {
+            return Err(invalid("not a newc archive"));
+        }
+        let field = |index: usize| {
+            let hex = &header[6 + index * 8..6 + (index + 1) * 8];
+            std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or(invalid("invalid header field"))
+        };
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = position + HEADER_SIZE;
+        let name = data
+            .get(name_start..name_start + name_size)
+            .ok_or(invalid("truncated name"))?;
+        let name = name
+            .strip_suffix(b"\0")
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .ok_or(invalid("invalid name"))?;
+
+        let data_start = align(name_start + name_size);
+        let contents = data
+            .get(data_start..data_start + size)
+            .ok_or(invalid("truncated data"))?;
+        position = align(data_start + size);
+
+        if name == TRAILER {
+            return Ok((position.min(data.len()), entries));
+        }
+
+        let kind = match mode & S_IFMT {
+            S_IFREG => EntryKind::File,
+            S_IFDIR => EntryKind::Directory,
+            S_IFLNK => EntryKind::Symlink {
+                target: String::from_utf8_lossy(contents).into_owned(),
+            },
+            _ => EntryKind::Other,
+        };
+        let entry = Entry {
+            name: normalize(name).to_string(),
+            kind,
+            permissions: mode & 0o7777,
+            size,
+        };
+        visit(&entry, contents);
+        entries.push(entry);
+    }
+}
+
+/// Round `position` up to the 4-byte alignment of `newc` archives.
+fn align(position: usize) -> usize {
+    position.next_multiple_of(4)
+}
+
+/// Strip the `./` or `/` prefix of an entry name.
+fn normalize(name: &str) -> &str {
+    let name = name.strip_prefix("./").unwrap_or(name);
+    match name.trim_start_matches('/') {
+        "" => ".",
+        name => name,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use super::{EntryKind, Initrd};
+    use crate::compression::Compression;
+    use crate::error::InitrdError;
+
+    /// A `newc` archive of `(name, mode, data)` entries.
+    pub(crate) fn cpio(entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let trailer = [("TRAILER!!!", 0, &b""[..])];
+        for (ino, (name, mode, data)) in entries.iter().chain(&trailer).enumerate() {
+            let fields = [
+                ino as u32,
+                *mode,
+                0,
+                0,
+                1,
+                0,
+                data.len() as u32,
+                0,
+                0,
+                0,
+                0,
+                name.len() as u32 + 1,
+                0,
+            ];
+            archive.extend_from_slice(b"070701");
+            for field in fields {
+                archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+            }
+            archive.extend_from_slice(name.as_bytes());
+            archive.push(0);
+            archive.resize(archive.len().next_multiple_of(4), 0);
+            archive.extend_from_slice(data);
+            archive.resize(archive.len().next_multiple_of(4), 0);
+        }
+        archive.resize(archive.len().next_multiple_of(512), 0);
+        archive
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn lz4_legacy(data: &[u8]) -> Vec<u8> {
+        let block = lz4_flex::block::compress(data);
+        let mut compressed = b"\x02\x21\x4c\x18".to_vec();
+        compressed.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        compressed.extend_from_slice(&block);
+        compressed
+    }
+
+    #[test]
+    fn segments() {
+        let microcode = cpio(&[
+            ("kernel", 0o40755, b""),
+            ("kernel/x86/microcode/GenuineIntel.bin", 0o100644, b"ucode"),
+        ]);
+        let main = cpio(&[
+            (".", 0o40755, b""),
+            ("./init", 0o100755, b"#!/bin/sh"),
+            ("./lib", 0o120777, b"/nix/store/xxx-modules-shrunk/lib"),
+            ("./nix/store/xxx-modules-shrunk/lib/modules", 0o40555, b""),
+            (
+                "./nix/store/xxx-modules-shrunk/lib/modules/6.6.8",
+                0o40555,
+                b"",
+            ),
+            ("./etc/hostname", 0o100644, b"nixos"),
+        ]);
+        let secrets = cpio(&[("etc/hostname", 0o100600, b"secret")]);
+
+        let mut data = microcode;
+        data.extend(ruzstd::encoding::compress_to_vec(
+            &main[..],
+            ruzstd::encoding::CompressionLevel::Fastest,
+        ));
+        data.extend_from_slice(&[0; 3]);
+        let secrets_offset = data.len();
+        data.extend(gzip(&secrets));
+        let initrd = Initrd::from_bytes(data);
+
+        let segments = initrd.segments().unwrap();
+        assert_eq!(
+            segments
+                .iter()
+                .map(|segment| (segment.compression, segment.entries.len()))
+                .collect::<Vec<_>>(),
+            vec![
+                (None, 2),
+                (Some(Compression::Zstd), 6),
+                (Some(Compression::Gzip), 1)
+            ]
+        );
+        assert_eq!(segments[2].offset, secrets_offset);
+
+        let lib = &segments[1].entries[2];
+        assert_eq!(lib.name, "lib");
+        assert_eq!(
+            lib.kind,
+            EntryKind::Symlink {
+                target: "/nix/store/xxx-modules-shrunk/lib".into()
+            }
+        );
+        assert_eq!(segments[1].entries[1].permissions, 0o755);
+
+        // Later segments win.
+        assert_eq!(
+            initrd.extract("/etc/hostname").unwrap().as_deref(),
+            Some(&b"secret"[..])
+        );
+        assert_eq!(
+            initrd
+                .extract("kernel/x86/microcode/GenuineIntel.bin")
+                .unwrap()
+                .as_deref(),
+            Some(&b"ucode"[..])
+        );
+        assert_eq!(initrd.extract("lib").unwrap(), None);
+        assert_eq!(initrd.extract("missing").unwrap(), None);
+
+        assert_eq!(initrd.kernel_module_versions().unwrap(), vec!["6.6.8"]);
+    }
+
+    #[test]
+    fn compressions() {
+        let archive = cpio(&[("lib/modules/6.6.8", 0o40555, b"")]);
+
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &archive[..], &mut xz).unwrap();
+        let mut lzma = Vec::new();
+        lzma_rs::lzma_compress(&mut &archive[..], &mut lzma).unwrap();
+
+        for (compression, data) in [
+            (Compression::Xz, xz),
+            (Compression::Lzma, lzma),
+            (Compression::Lz4, lz4_legacy(&archive)),
+        ] {
+            let initrd = Initrd::from_bytes(data);
+            let segments = initrd.segments().unwrap();
+            assert_eq!(segments.len(), 1);
+            assert_eq!(segments[0].compression, Some(compression));
+            assert_eq!(initrd.kernel_module_versions().unwrap(), vec!["6.6.8"]);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let archive = cpio(&[("init", 0o100755, b"#!/bin/sh")]);
+
+        let mut truncated = archive.clone();
+        truncated.truncate(120);
+        assert!(matches!(
+            Initrd::from_bytes(truncated).segments(),
+            Err(InitrdError::InvalidCpio { offset: 0, .. })
+        ));
+
+        let mut corrupt = gzip(&archive);
+        let len = corrupt.len();
+        corrupt[len - 12] ^= 0xff;
+        assert!(matches!(
+            Initrd::from_bytes(corrupt).segments(),
+            Err(InitrdError::Decompress {
+                offset: 0,
+                compression: Compression::Gzip,
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            Initrd::from_bytes(b"BZh91AY&SY".to_vec()).segments(),
+            Err(InitrdError::UnsupportedCompression {
+                compression: Compression::Bzip2,
+                ..
+            })
+        ));
+
+        let mut junk = archive;
+        junk.extend_from_slice(b"junk");
+        let offset = junk.len() - 4;
+        assert!(matches!(
+            Initrd::from_bytes(junk).segments(),
+            Err(InitrdError::UnknownSegment(o)) if o == offset
+        ));
+    }
+}
@@ -0,0 +1,341 @@
+//! Running the `initrdSecrets` tool of a bootspec.
+//!
+//! The schema asks consumers to copy the initrd to a writable location, run the tool with the
+//! copy's path as its only argument, boot the modified copy, and not generate a boot entry at all
+//! if the tool fails. [`BootSpecV1::append_initrd_secrets`] does all of that.
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::InitrdSecretsError;
+use crate::kernel::Architecture;
+use crate::v1::BootSpecV1;
+use crate::Result;
+
+/// How often a running tool is checked for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long to wait for the tool's stderr to close after it exits, in case a child process it
+/// left behind still holds it open.
+const STDERR_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Settings for [`BootSpecV1::append_initrd_secrets`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitrdSecretsOptions {
+    /// The directory the modified initrd is created in. It contains secrets, so it is only
+    /// readable by the current user. Creating it next to its final location allows it to be
+    /// renamed into place atomically.
+    pub dir: PathBuf,
+    /// How long the tool may run before it is killed, or `None` to wait indefinitely.
+    pub timeout: Option<Duration>,
+    /// Whether to refuse to run a tool built for a `system` that cannot run on this machine,
+    /// rather than letting it fail. Turn this off if such binaries run through emulation.
+    pub check_system: bool,
+}
+
+impl InitrdSecretsOptions {
+    /// Options creating the modified initrd in `dir`, which should be on the same filesystem as
+    /// its final location. The tool may run for a minute, and must be able to run on this machine.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            timeout: Some(Duration::from_secs(60)),
+            check_system: true,
+        }
+    }
+}
+
+impl BootSpecV1 {
+    /// Run [`BootSpecV1::initrd_secrets`] against a copy of [`BootSpecV1::initrd`] in
+    /// [`InitrdSecretsOptions::dir`], returning the path to the modified copy. The caller owns
+    /// the copy and should remove it once it has been installed.
+    ///
+    /// Returns `None` if there is no tool to run, in which case the initrd should be used as is.
+    /// If the tool fails, the copy is removed and an error is returned that includes its stderr;
+    /// no boot entry should be generated for this bootspec then.
+    ///
+    /// ## Warnings
+    ///
+    /// The bootloader backends ([`crate::grub`], [`crate::systemd_boot`], [`crate::extlinux`] and
+    /// [`crate::uki`]) do not run the tool. If [`BootSpecV1::initrd_secrets`] is set, the caller is
+    /// responsible for calling this and passing the returned copy to the backend (e.g. in
+    /// [`crate::grub::Config::initrds_with_secrets`]), which returns an error otherwise.
+    pub fn append_initrd_secrets(&self, options: &InitrdSecretsOptions) -> Result<Option<PathBuf>> {
+        let Some(tool) = &self.initrd_secrets else {
+            return Ok(None);
+        };
+        let initrd = self
+            .initrd
+            .as_ref()
+            .ok_or(InitrdSecretsError::MissingInitrd)?;
+
+        if options.check_system && !can_run(&self.system) {
+            return Err(InitrdSecretsError::IncompatibleSystem {
+                tool: tool.clone(),
+                system: self.system.clone(),
+                host: host_system(),
+            }
+            .into());
+        }
+
+        let copy_error = |err| InitrdSecretsError::CopyInitrd {
+            initrd: initrd.clone(),
+            dir: options.dir.clone(),
+            err,
+        };
+        // Dropping the file before it is kept removes it, so every error below cleans up.
+        let copy = tempfile::Builder::new()
+            .prefix("initrd-secrets-")
+            .tempfile_in(&options.dir)
+            .map_err(copy_error)?;
+        io::copy(
+            &mut File::open(initrd).map_err(copy_error)?,
+            &mut copy.as_file(),
+        )
+        .map_err(copy_error)?;
+        copy.as_file().sync_all().map_err(copy_error)?;
+
+        run(tool, copy.path(), options.timeout)?;
+
+        let path = copy
+            .into_temp_path()
+            .keep()
+            .map_err(|e| copy_error(e.error))?;
+        Ok(Some(path))
+    }
+}
+
+/// Run `tool` with `initrd` as its only argument, killing it after `timeout`.
+fn run(tool: &Path, initrd: &Path, timeout: Option<Duration>) -> Result<(), InitrdSecretsError> {
+    let mut child = Command::new(tool)
+        .arg(initrd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| InitrdSecretsError::Spawn {
+            tool: tool.to_path_buf(),
+            err,
+        })?;
+
+    // Read stderr on another thread, so that a chatty tool cannot block on a full pipe.
+    let stderr = Arc::new(Mutex::new(Vec::new()));
+    let (done, closed) = mpsc::channel();
+    if let Some(mut pipe) = child.stderr.take() {
+        let stderr = Arc::clone(&stderr);
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok(n @ 1..) = pipe.read(&mut buf) {
+                stderr
+                    .lock()
+                    .expect("stderr lock")
+                    .extend_from_slice(&buf[..n]);
+            }
+            let _ = done.send(());
+        });
+    }
+    let captured = || {
+        let _ = closed.recv_timeout(STDERR_GRACE_PERIOD);
+        String::from_utf8_lossy(&stderr.lock().expect("stderr lock")).into_owned()
+    };
+
+    let started = Instant::now();
+    let status = loop {
+        let spawn_error = |err| InitrdSecretsError::Spawn {
+            tool: tool.to_path_buf(),
+            err,
+        };
+        if let Some(status) = child.try_wait().map_err(spawn_error)? {
+            break status;
+        }
+
+        if let Some(timeout) = timeout {
+            if started.elapsed() >= timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(InitrdSecretsError::TimedOut {
+                    tool: tool.to_path_buf(),
+                    timeout,
+                    stderr: captured(),
+                });
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    if !status.success() {
+        return Err(InitrdSecretsError::Failed {
+            tool: tool.to_path_buf(),
+            status,
+            stderr: captured(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Whether this machine can run binaries built for the Nix system double `system`.
+fn can_run(system: &str) -> bool {
+    if !system.ends_with("-linux") || !cfg!(target_os = "linux") {
+        return system == host_system();
+    }
+
+    match Architecture::host() {
+        Some(Architecture::X86_64) => {
+            Architecture::X86_64.matches_system(system) || Architecture::X86.matches_system(system)
+        }
+        Some(host) => host.matches_system(system),
+        None => system == host_system(),
+    }
+}
+
+/// The Nix system double of this machine, e.g. `x86_64-linux`.
+fn host_system() -> String {
+    let cpu = Architecture::host()
+        .map(Architecture::nix_cpu)
+        .unwrap_or(std::env::consts::ARCH);
+    format!("{}-{}", cpu, std::env::consts::OS)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+
+    use super::{can_run, host_system, InitrdSecretsOptions};
+    use crate::error::{BootspecError, InitrdSecretsError};
+    use crate::v1::BootSpecV1;
+
+    /// Executing a script fails with `ETXTBSY` if another thread forked while it was being
+    /// written, so the tests that write and run scripts take turns.
+    static SCRIPTS: Mutex<()> = Mutex::new(());
+
+    fn lock() -> MutexGuard<'static, ()> {
+        SCRIPTS.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn bootspec(dir: &Path, script: &str) -> BootSpecV1 {
+        let initrd = dir.join("initrd");
+        fs::write(&initrd, "initrd\n").unwrap();
+        let tool = dir.join("append-initrd-secrets");
+        fs::write(&tool, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+
+        BootSpecV1::builder()
+            .label("NixOS")
+            .kernel("/nix/store/xxx-linux/bzImage")
+            .init("/nix/store/xxx-nixos-system-xxx/init")
+            .initrd(initrd)
+            .initrd_secrets(tool)
+            .system(host_system())
+            .toplevel("/nix/store/xxx-nixos-system-xxx")
+            .build()
+            .unwrap()
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn appends_secrets() {
+        let _lock = lock();
+        let dir = TempDir::new().unwrap();
+        let out = TempDir::new().unwrap();
+        let bootspec = bootspec(dir.path(), "echo secrets >> \"$1\"\n");
+        let options = InitrdSecretsOptions::new(out.path());
+
+        let initrd = bootspec.append_initrd_secrets(&options).unwrap().unwrap();
+        assert_eq!(initrd.parent(), Some(out.path()));
+        assert_eq!(fs::read_to_string(&initrd).unwrap(), "initrd\nsecrets\n");
+        assert_eq!(
+            fs::read_to_string(bootspec.initrd.as_ref().unwrap()).unwrap(),
+            "initrd\n"
+        );
+
+        let mut without_tool = bootspec.clone();
+        without_tool.initrd_secrets = None;
+        assert_eq!(without_tool.append_initrd_secrets(&options).unwrap(), None);
+
+        let mut without_initrd = bootspec;
+        without_initrd.initrd = None;
+        assert!(matches!(
+            without_initrd.append_initrd_secrets(&options),
+            Err(BootspecError::InitrdSecrets(
+                InitrdSecretsError::MissingInitrd
+            ))
+        ));
+    }
+
+    #[test]
+    fn failures_clean_up() {
+        let _lock = lock();
+        let dir = TempDir::new().unwrap();
+        let out = TempDir::new().unwrap();
+        let options = InitrdSecretsOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..InitrdSecretsOptions::new(out.path())
+        };
+
+        let failing = bootspec(dir.path(), "echo 'no /etc/secrets' >&2\nexit 3\n");
+        match failing.append_initrd_secrets(&options) {
+            Err(BootspecError::InitrdSecrets(InitrdSecretsError::Failed {
+                status,
+                stderr,
+                ..
+            })) => {
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(stderr, "no /etc/secrets\n");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(files(out.path()).is_empty());
+
+        let hanging = bootspec(dir.path(), "echo started >&2\nexec sleep 10\n");
+        match hanging.append_initrd_secrets(&options) {
+            Err(BootspecError::InitrdSecrets(InitrdSecretsError::TimedOut { stderr, .. })) => {
+                assert_eq!(stderr, "started\n");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(files(out.path()).is_empty());
+    }
+
+    #[test]
+    fn checks_system() {
+        let _lock = lock();
+        let dir = TempDir::new().unwrap();
+        let mut bootspec = bootspec(dir.path(), "exit 0\n");
+        bootspec.system = "mips64el-linux".into();
+
+        let options = InitrdSecretsOptions::new(dir.path());
+        assert!(matches!(
+            bootspec.append_initrd_secrets(&options),
+            Err(BootspecError::InitrdSecrets(
+                InitrdSecretsError::IncompatibleSystem { .. }
+            ))
+        ));
+
+        let options = InitrdSecretsOptions {
+            check_system: false,
+            ..options
+        };
+        assert!(bootspec.append_initrd_secrets(&options).unwrap().is_some());
+
+        assert!(can_run(&host_system()));
+        assert!(!can_run("mips64el-linux"));
+    }
+}
@@ -0,0 +1,424 @@
+//! Inspect kernel images: their format, architecture and embedded version.
+//!
+//! This only reads headers and embedded strings; compressed payloads are not decompressed, so the
+//! architecture or version of a compressed image may be unknown.
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::compression::Compression;
+use crate::error::{BootspecError, KernelError};
+use crate::v1::BootSpecV1;
+use crate::Result;
+
+/// The CPU architecture a kernel image was built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Architecture {
+    X86,
+    X86_64,
+    Arm,
+    Aarch64,
+    Riscv64,
+    LoongArch64,
+}
+
+impl Architecture {
+    /// The CPU part of the usual Nix system double for this architecture, e.g. `x86_64` for
+    /// `x86_64-linux`.
+    pub fn nix_cpu(self) -> &'static str {
+        match self {
+            Architecture::X86 => "i686",
+            Architecture::X86_64 => "x86_64",
+            Architecture::Arm => "armv7l",
+            Architecture::Aarch64 => "aarch64",
+            Architecture::Riscv64 => "riscv64",
+            Architecture::LoongArch64 => "loongarch64",
+        }
+    }
+
+    /// Whether the Nix system double `system` (e.g. `x86_64-linux`) runs on this architecture.
+    pub fn matches_system(self, system: &str) -> bool {
+        let cpu = system.split('-').next().unwrap_or_default();
+        match self {
+            Architecture::X86 => matches!(cpu, "i386" | "i486" | "i586" | "i686"),
+            Architecture::Arm => cpu.starts_with("arm"),
+            _ => cpu == self.nix_cpu(),
+        }
+    }
+
+    /// The architecture this program is running on, if it is one of the above.
+    pub fn host() -> Option<Self> {
+        match std::env::consts::ARCH {
+            "x86" => Some(Architecture::X86),
+            "x86_64" => Some(Architecture::X86_64),
+            "arm" => Some(Architecture::Arm),
+            "aarch64" => Some(Architecture::Aarch64),
+            "riscv64" => Some(Architecture::Riscv64),
+            "loongarch64" => Some(Architecture::LoongArch64),
+            _ => None,
+        }
+    }
+
+    /// The architecture of a PE/COFF `Machine` field.
+    fn from_pe_machine(machine: u16) -> Option<Self> {
+        match machine {
+            0x014c => Some(Architecture::X86),
+            0x8664 => Some(Architecture::X86_64),
+            0x01c0 | 0x01c2 | 0x01c4 => Some(Architecture::Arm),
+            0xaa64 => Some(Architecture::Aarch64),
+            0x5064 => Some(Architecture::Riscv64),
+            0x6264 => Some(Architecture::LoongArch64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.nix_cpu())
+    }
+}
+
+/// The format of a kernel image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KernelFormat {
+    /// An x86 `bzImage`.
+    BzImage,
+    /// An arm64 `Image`.
+    Arm64Image,
+    /// Any other PE/COFF executable, e.g. a unified kernel image.
+    Pe,
+    /// A compressed kernel, either compressed as a whole (e.g. `Image.gz`) or wrapped in an EFI
+    /// zboot image.
+    Compressed(Compression),
+}
+
+/// What [`inspect`] found out about a kernel image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelImage {
+    /// The image format.
+    pub format: KernelFormat,
+    /// Whether the image is also a PE/COFF executable that UEFI firmware can boot directly.
+    pub efi_stub: bool,
+    /// The architecture the kernel was built for, if known.
+    pub architecture: Option<Architecture>,
+    /// The kernel release, i.e. what `uname -r` prints when running it, if known.
+    pub version: Option<String>,
+}
+
+impl BootSpecV1 {
+    /// Inspect the kernel image referenced by [`BootSpecV1::kernel`].
+    pub fn inspect_kernel(&self) -> Result<KernelImage> {
+        inspect(&self.kernel)
+    }
+}
+
+/// Inspect the kernel image at `path`.
+pub fn inspect(path: &Path) -> Result<KernelImage> {
+    let image = fs::read(path).map_err(|e| BootspecError::ReadPath {
+        path: path.to_path_buf(),
+        err: e,
+    })?;
+    Ok(inspect_bytes(&image)?)
+}
+
+/// Inspect the kernel image `image`.
+pub fn inspect_bytes(image: &[u8]) -> Result<KernelImage, KernelError> {
+    let pe = Pe::parse(image);
+
+    if image.get(0x202..0x206) == Some(b"HdrS") {
+        return Ok(KernelImage {
+            format: KernelFormat::BzImage,
+            efi_stub: pe.is_some(),
+            architecture: Some(bzimage_architecture(image)?),
+            version: bzimage_version(image),
+        });
+    }
+
+    if image.get(0x38..0x3c) == Some(b"ARM\x64") {
+        return Ok(KernelImage {
+            format: KernelFormat::Arm64Image,
+            efi_stub: pe.is_some(),
+            architecture: Some(Architecture::Aarch64),
+            version: banner_version(image),
+        });
+    }
+
+    if let Some(pe) = pe {
+        let architecture = Architecture::from_pe_machine(pe.machine);
+
+        // EFI zboot: a small decompressor with the compressed kernel as its payload.
+        if image.get(4..8) == Some(b"zimg") {
+            let name = image
+                .get(0x18..0x38)
+                .ok_or(KernelError::Malformed("truncated zboot header"))?;
+            let name = c_str(name).ok_or(KernelError::Malformed("invalid compression type"))?;
+            let compression = Compression::from_name(name).ok_or(KernelError::UnknownFormat)?;
+            return Ok(KernelImage {
+                format: KernelFormat::Compressed(compression),
+                efi_stub: true,
+                architecture,
+                version: None,
+            });
+        }
+
+        let version = match pe.section(image, b".uname") {
+            Some(uname) => c_str(uname).map(|uname| uname.trim().to_string()),
+            None => banner_version(image),
+        };
+        return Ok(KernelImage {
+            format: KernelFormat::Pe,
+            efi_stub: true,
+            architecture,
+            version,
+        });
+    }
+
+    if let Some(compression) = Compression::detect(image) {
+        return Ok(KernelImage {
+            format: KernelFormat::Compressed(compression),
+            efi_stub: false,
+            architecture: None,
+            version: None,
+        });
+    }
+
+    Err(KernelError::UnknownFormat)
+}
+
+/// The architecture of an x86 bzImage, from the `xloadflags` of its setup header.
+fn bzimage_architecture(image: &[u8]) -> Result<Architecture, KernelError> {
+    const XLF_KERNEL_64: u8 = 1;
+
+    // `xloadflags` was added in version 2.12 of the boot protocol.
+    let protocol =
+        read_u16(image, 0x206).ok_or(KernelError::Malformed("truncated setup header"))?;
+    let xloadflags = match protocol {
+        0x020c.. => *image
+            .get(0x236)
+            .ok_or(KernelError::Malformed("truncated setup header"))?,
+        _ => 0,
+    };
+
+    Ok(if xloadflags & XLF_KERNEL_64 != 0 {
+        Architecture::X86_64
+    } else {
+        Architecture::X86
+    })
+}
+
+/// The version string an x86 bzImage points to from its setup header.
+fn bzimage_version(image: &[u8]) -> Option<String> {
+    // `kernel_version` is an offset from the start of the setup code, 0x200 bytes in.
+    let offset = read_u16(image, 0x20e)?;
+    if offset == 0 {
+        return None;
+    }
+    first_word(image.get(usize::from(offset) + 0x200..)?)
+}
+
+/// The version in the `Linux version ...` banner of an uncompressed kernel image.
+fn banner_version(image: &[u8]) -> Option<String> {
+    const BANNER: &[u8] = b"Linux version ";
+
+    let start = image
+        .windows(BANNER.len())
+        .position(|window| window == BANNER)?;
+    first_word(&image[start + BANNER.len()..])
+}
+
+fn first_word(bytes: &[u8]) -> Option<String> {
+    let end = bytes
+        .iter()
+        .position(|&b| b == 0 || b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let word = std::str::from_utf8(&bytes[..end]).ok()?;
+    (!word.is_empty()).then(|| word.to_string())
+}
+
+/// The UTF-8 string before the first NUL byte of `bytes`.
+fn c_str(bytes: &[u8]) -> Option<&str> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..end]).ok()
+}
+
+/// The parts of a PE/COFF image's headers needed to identify it.
+struct Pe {
+    machine: u16,
+    section_table: usize,
+    number_of_sections: usize,
+}
+
+impl Pe {
+    fn parse(image: &[u8]) -> Option<Self> {
+        if image.get(0..2) != Some(b"MZ") {
+            return None;
+        }
+        let pe = read_u32(image, 0x3c)? as usize;
+        if image.get(pe..pe + 4) != Some(b"PE\0\0") {
+            return None;
+        }
+
+        let coff = pe + 4;
+        let size_of_optional_header = read_u16(image, coff + 16)? as usize;
+        Some(Self {
+            machine: read_u16(image, coff)?,
+            section_table: coff + 20 + size_of_optional_header,
+            number_of_sections: read_u16(image, coff + 2)? as usize,
+        })
+    }
+
+    /// The contents of the section called `name`, if present.
+    fn section<'a>(&self, image: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+        (0..self.number_of_sections).find_map(|i| {
+            let header = self.section_table + i * 40;
+            let header_name = image.get(header..header + 8)?;
+            if c_str(header_name)?.as_bytes() != name {
+                return None;
+            }
+
+            let size = read_u32(image, header + 16)? as usize;
+            let offset = read_u32(image, header + 20)? as usize;
+            image.get(offset..offset.checked_add(size)?)
+        })
+    }
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Option<u16> {
+    image
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Option<u32> {
+    image
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inspect_bytes, Architecture, KernelFormat, KernelImage};
+    use crate::compression::Compression;
+    use crate::error::KernelError;
+    use crate::uki::{add_sections, Section};
+
+    /// A minimal PE32+ image for `machine` with no sections.
+    fn pe(machine: u16) -> Vec<u8> {
+        let mut image = vec![0; 0x400];
+        image[0..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        image[0x84..0x86].copy_from_slice(&machine.to_le_bytes());
+        // SizeOfOptionalHeader, then the optional header.
+        image[0x94..0x96].copy_from_slice(&240u16.to_le_bytes());
+        image[0x98..0x9a].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[0x98 + 32..0x98 + 36].copy_from_slice(&0x1000u32.to_le_bytes());
+        image[0x98 + 36..0x98 + 40].copy_from_slice(&0x200u32.to_le_bytes());
+        image[0x98 + 60..0x98 + 64].copy_from_slice(&0x400u32.to_le_bytes());
+        image[0x98 + 108..0x98 + 112].copy_from_slice(&16u32.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn bzimage() {
+        let mut image = pe(0x8664);
+        image[0x202..0x206].copy_from_slice(b"HdrS");
+        image[0x206..0x208].copy_from_slice(&0x020fu16.to_le_bytes());
+        image[0x20e..0x210].copy_from_slice(&0x100u16.to_le_bytes());
+        image[0x236] = 1;
+        image[0x300..0x314].copy_from_slice(b"6.1.55 (nixbld@local");
+
+        assert_eq!(
+            inspect_bytes(&image).unwrap(),
+            KernelImage {
+                format: KernelFormat::BzImage,
+                efi_stub: true,
+                architecture: Some(Architecture::X86_64),
+                version: Some("6.1.55".into()),
+            }
+        );
+
+        // A 32-bit kernel without an EFI stub or a version string.
+        image[0..2].copy_from_slice(b"\0\0");
+        image[0x236] = 0;
+        image[0x20e..0x210].copy_from_slice(&0u16.to_le_bytes());
+        let inspected = inspect_bytes(&image).unwrap();
+        assert!(!inspected.efi_stub);
+        assert_eq!(inspected.architecture, Some(Architecture::X86));
+        assert_eq!(inspected.version, None);
+    }
+
+    #[test]
+    fn arm64_image() {
+        let mut image = vec![0; 0x100];
+        image[0x38..0x3c].copy_from_slice(b"ARM\x64");
+        image.extend_from_slice(b"Linux version 6.6.8 (nixbld@localhost) #1-NixOS SMP\0");
+
+        let inspected = inspect_bytes(&image).unwrap();
+        assert_eq!(inspected.format, KernelFormat::Arm64Image);
+        assert!(!inspected.efi_stub);
+        assert_eq!(inspected.architecture, Some(Architecture::Aarch64));
+        assert_eq!(inspected.version.as_deref(), Some("6.6.8"));
+    }
+
+    #[test]
+    fn pe_images() {
+        let uki = add_sections(
+            &pe(0xaa64),
+            &[Section {
+                name: ".uname".into(),
+                data: b"6.6.8\n".to_vec(),
+            }],
+        )
+        .unwrap();
+        assert_eq!(
+            inspect_bytes(&uki).unwrap(),
+            KernelImage {
+                format: KernelFormat::Pe,
+                efi_stub: true,
+                architecture: Some(Architecture::Aarch64),
+                version: Some("6.6.8".into()),
+            }
+        );
+
+        let mut zboot = pe(0x5064);
+        zboot[4..8].copy_from_slice(b"zimg");
+        zboot[0x18..0x1e].copy_from_slice(b"zstd22");
+        assert_eq!(
+            inspect_bytes(&zboot).unwrap(),
+            KernelImage {
+                format: KernelFormat::Compressed(Compression::Zstd),
+                efi_stub: true,
+                architecture: Some(Architecture::Riscv64),
+                version: None,
+            }
+        );
+    }
+
+    #[test]
+    fn compressed_and_unknown() {
+        let inspected = inspect_bytes(b"\x1f\x8b\x08\x00").unwrap();
+        assert_eq!(
+            inspected.format,
+            KernelFormat::Compressed(Compression::Gzip)
+        );
+        assert_eq!(inspected.architecture, None);
+
+        assert!(matches!(
+            inspect_bytes(b"#!/bin/sh"),
+            Err(KernelError::UnknownFormat)
+        ));
+    }
+
+    #[test]
+    fn architectures_match_systems() {
+        assert!(Architecture::X86_64.matches_system("x86_64-linux"));
+        assert!(Architecture::X86.matches_system("i686-linux"));
+        assert!(Architecture::Arm.matches_system("armv6l-linux"));
+        assert!(!Architecture::Aarch64.matches_system("x86_64-linux"));
+        assert!(!Architecture::X86.matches_system("x86_64-linux"));
+    }
+}
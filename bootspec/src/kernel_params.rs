@@ -0,0 +1,378 @@
+//! Provides types for the kernel parameters of a bootspec and for Linux kernel command lines.
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+
+use serde::{Deserialize, Serialize};
+
+/// A single kernel parameter, either a flag (e.g. `quiet`) or a key-value pair (e.g.
+/// `loglevel=4`).
+///
+/// The parameter is stored exactly as it was given, including any double quotes (e.g.
+/// `dyndbg="file foo.c +p"`), so that it serializes to the same string it was deserialized or
+/// parsed from. [`KernelParam::key`] and [`KernelParam::value`] strip the quotes the way the
+/// kernel does.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct KernelParam(String);
+
+impl KernelParam {
+    /// Create a flag parameter, e.g. `quiet`.
+    pub fn flag(key: impl Into<String>) -> Self {
+        let key = key.into();
+        if needs_quotes(&key) {
+            Self(format!("\"{}\"", key))
+        } else {
+            Self(key)
+        }
+    }
+
+    /// Create a key-value parameter, e.g. `loglevel=4`. Values containing whitespace are quoted.
+    pub fn pair(key: impl Into<String>, value: impl Into<String>) -> Self {
+        let (key, value) = (key.into(), value.into());
+        // The kernel only strips quotes around the whole parameter or around the value.
+        if key.contains(char::is_whitespace) {
+            Self(format!("\"{}={}\"", key, value))
+        } else if value.contains(char::is_whitespace) {
+            Self(format!("{}=\"{}\"", key, value))
+        } else {
+            Self(format!("{}={}", key, value))
+        }
+    }
+
+    /// The parameter as it was given, including any quotes.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The parameter's key, e.g. `loglevel` for `loglevel=4`.
+    pub fn key(&self) -> &str {
+        self.split().0
+    }
+
+    /// The parameter's value without its quotes, e.g. `4` for `loglevel=4`, or `None` for a flag.
+    pub fn value(&self) -> Option<&str> {
+        self.split().1
+    }
+
+    /// Whether the parameter has no value, e.g. `quiet`.
+    pub fn is_flag(&self) -> bool {
+        self.value().is_none()
+    }
+
+    /// Split the parameter into its key and value the way the kernel does, dropping the quotes
+    /// around the whole parameter or around its value.
+    fn split(&self) -> (&str, Option<&str>) {
+        let (quoted, raw) = match self.0.strip_prefix('"') {
+            Some(raw) => (true, raw),
+            None => (false, self.0.as_str()),
+        };
+
+        match raw.split_once('=') {
+            Some((key, value)) => {
+                let value = match value.strip_prefix('"') {
+                    Some(unquoted) => unquoted.strip_suffix('"').unwrap_or(unquoted),
+                    None if quoted => value.strip_suffix('"').unwrap_or(value),
+                    None => value,
+                };
+                (key, Some(value))
+            }
+            None if quoted => (raw.strip_suffix('"').unwrap_or(raw), None),
+            None => (raw, None),
+        }
+    }
+
+    /// Whether the parameter already contains quotes around the whole parameter or its value.
+    fn is_quoted(&self) -> bool {
+        self.0.starts_with('"')
+            || self
+                .0
+                .split_once('=')
+                .is_some_and(|(_, value)| value.starts_with('"'))
+    }
+}
+
+#[cfg(feature = "schemars")]
+impl schemars::JsonSchema for KernelParam {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "KernelParameter".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "string",
+            "pattern": r"^[a-zA-Z0-9._-]+(=[^\s=]+)?$",
+            "description": "A kernel parameter in the form key[=value], e.g., loglevel=4 or quiet",
+        })
+    }
+}
+
+fn needs_quotes(s: &str) -> bool {
+    s.is_empty() || s.contains(char::is_whitespace)
+}
+
+impl From<String> for KernelParam {
+    fn from(param: String) -> Self {
+        Self(param)
+    }
+}
+
+impl From<&str> for KernelParam {
+    fn from(param: &str) -> Self {
+        Self::from(param.to_string())
+    }
+}
+
+impl From<KernelParam> for String {
+    fn from(param: KernelParam) -> Self {
+        param.0
+    }
+}
+
+impl fmt::Display for KernelParam {
+    /// Render the parameter for a kernel command line, quoting it if it contains whitespace and
+    /// is not quoted already.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !needs_quotes(&self.0) || self.is_quoted() {
+            return write!(f, "{}", self.0);
+        }
+
+        match self.split() {
+            (key, None) => write!(f, "{}", KernelParam::flag(key).0),
+            (key, Some(value)) => write!(f, "{}", KernelParam::pair(key, value).0),
+        }
+    }
+}
+
+/// An ordered list of kernel parameters.
+///
+/// This serializes to the same JSON array of strings as the `kernelParams` field always has.
+///
+/// Like the kernel, lookups use last-one-wins semantics: if a key is given more than once, the
+/// last occurrence is the effective one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct KernelParams(Vec<KernelParam>);
+
+impl KernelParams {
+    /// Create an empty list of kernel parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a kernel command line, such as the contents of `/proc/cmdline` or a generation's
+    /// `kernel-params` file.
+    ///
+    /// Parameters are separated by any amount of whitespace. Double quotes may be used to include
+    /// whitespace in a parameter (`"foo bar"`) or in its value (`foo="bar baz"`). They are kept in
+    /// the parameters, but not in their [`KernelParam::key`] or [`KernelParam::value`].
+    pub fn parse(cmdline: &str) -> Self {
+        let mut params = Vec::new();
+        let mut rest = cmdline.trim_start();
+
+        while !rest.is_empty() {
+            let mut in_quote = false;
+            let end = rest
+                .char_indices()
+                .find(|&(_, c)| {
+                    if c == '"' {
+                        in_quote = !in_quote;
+                    }
+                    c.is_whitespace() && !in_quote
+                })
+                .map(|(i, _)| i)
+                .unwrap_or(rest.len());
+
+            params.push(KernelParam::from(&rest[..end]));
+            rest = rest[end..].trim_start();
+        }
+
+        Self(params)
+    }
+
+    /// Append a parameter.
+    pub fn push(&mut self, param: impl Into<KernelParam>) {
+        self.0.push(param.into());
+    }
+
+    /// The effective parameter with the given key, i.e. its last occurrence.
+    pub fn get(&self, key: &str) -> Option<&KernelParam> {
+        self.0.iter().rev().find(|param| param.key() == key)
+    }
+
+    /// Every occurrence of the parameter with the given key, in order.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a KernelParam> + 'a {
+        self.0.iter().filter(move |param| param.key() == key)
+    }
+
+    /// The effective value of the parameter with the given key, or `None` if it is absent or a
+    /// flag.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(KernelParam::value)
+    }
+
+    /// Whether a parameter with the given key is present, either as a flag or with a value.
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The keys that are given more than once, in order of first occurrence.
+    pub fn duplicates(&self) -> Vec<&str> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for param in &self.0 {
+            *counts.entry(param.key()).or_default() += 1;
+        }
+
+        let mut duplicates = Vec::new();
+        for param in &self.0 {
+            if counts[param.key()] > 1 && !duplicates.contains(&param.key()) {
+                duplicates.push(param.key());
+            }
+        }
+        duplicates
+    }
+}
+
+impl Deref for KernelParams {
+    type Target = [KernelParam];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl fmt::Display for KernelParams {
+    /// Render the parameters as a kernel command line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, param) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", param)?;
+        }
+        Ok(())
+    }
+}
+
+impl<P: Into<KernelParam>> FromIterator<P> for KernelParams {
+    fn from_iter<I: IntoIterator<Item = P>>(iter: I) -> Self {
+        Self(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<P: Into<KernelParam>> From<Vec<P>> for KernelParams {
+    fn from(params: Vec<P>) -> Self {
+        params.into_iter().collect()
+    }
+}
+
+impl IntoIterator for KernelParams {
+    type Item = KernelParam;
+    type IntoIter = std::vec::IntoIter<KernelParam>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a KernelParams {
+    type Item = &'a KernelParam;
+    type IntoIter = std::slice::Iter<'a, KernelParam>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KernelParam, KernelParams};
+
+    #[test]
+    fn parse_cmdline() {
+        let params = KernelParams::parse(
+            "  loglevel=4   quiet\tfoo=\"bar baz\" \"spaced key=x y\" empty= \"flag\"\n",
+        );
+
+        assert_eq!(
+            params.to_vec(),
+            vec![
+                KernelParam::pair("loglevel", "4"),
+                KernelParam::flag("quiet"),
+                KernelParam::pair("foo", "bar baz"),
+                KernelParam::pair("spaced key", "x y"),
+                KernelParam::pair("empty", ""),
+                KernelParam::from("\"flag\""),
+            ]
+        );
+        assert_eq!(params[5].key(), "flag");
+        assert!(params[5].is_flag());
+        assert_eq!(
+            params.to_string(),
+            "loglevel=4 quiet foo=\"bar baz\" \"spaced key=x y\" empty= \"flag\""
+        );
+        assert_eq!(KernelParams::parse(&params.to_string()), params);
+
+        assert!(KernelParams::parse("").is_empty());
+        assert!(KernelParams::parse(" \n").is_empty());
+    }
+
+    #[test]
+    fn lookup() {
+        let params = KernelParams::parse("console=tty0 quiet console=ttyS0,115200 root=/dev/sda1");
+
+        assert_eq!(params.value("console"), Some("ttyS0,115200"));
+        assert_eq!(
+            params
+                .get_all("console")
+                .map(|p| p.value().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["tty0", "ttyS0,115200"]
+        );
+        assert!(params.contains("quiet"));
+        assert!(params.get("quiet").unwrap().is_flag());
+        assert_eq!(params.value("quiet"), None);
+        assert!(!params.contains("splash"));
+        assert_eq!(params.duplicates(), vec!["console"]);
+    }
+
+    #[test]
+    fn json_compatibility() {
+        let json = r#"["amd_iommu=on","quiet","foo=bar baz","a=b=c"]"#;
+        let params: KernelParams = serde_json::from_str(json).unwrap();
+
+        assert_eq!(params[2].key(), "foo");
+        assert_eq!(params[2].value(), Some("bar baz"));
+        assert_eq!(params[3], KernelParam::pair("a", "b=c"));
+        assert_eq!(serde_json::to_string(&params).unwrap(), json);
+        assert_eq!(
+            params.to_string(),
+            "amd_iommu=on quiet foo=\"bar baz\" a=b=c"
+        );
+    }
+
+    #[test]
+    fn quoted_values_round_trip() {
+        let json = r#"["dyndbg=\"file foo.c +p\"","\"spaced key=x y\""]"#;
+        let params: KernelParams = serde_json::from_str(json).unwrap();
+
+        assert_eq!(params[0].key(), "dyndbg");
+        assert_eq!(params[0].value(), Some("file foo.c +p"));
+        assert_eq!(params[1].key(), "spaced key");
+        assert_eq!(params[1].value(), Some("x y"));
+        assert_eq!(serde_json::to_string(&params).unwrap(), json);
+        assert_eq!(
+            params.to_string(),
+            "dyndbg=\"file foo.c +p\" \"spaced key=x y\""
+        );
+
+        let cmdline = "dyndbg=\"file foo.c +p\" quiet";
+        let params = KernelParams::parse(cmdline);
+        assert_eq!(params.value("dyndbg"), Some("file foo.c +p"));
+        assert_eq!(
+            serde_json::to_string(&params).unwrap(),
+            r#"["dyndbg=\"file foo.c +p\"","quiet"]"#
+        );
+        assert_eq!(params.to_string(), cmdline);
+    }
+}
@@ -1,22 +1,45 @@
+mod boot_files;
+pub mod compression;
 mod deser;
 pub mod error;
+pub mod extension;
+pub mod extlinux;
 pub mod generation;
+pub mod grub;
+#[cfg(feature = "initrd")]
+pub mod initrd;
+pub mod initrd_secrets;
+pub mod kernel;
+pub mod kernel_params;
+pub mod migration;
+pub mod profile;
+#[cfg(any(feature = "json-schema", feature = "schemars"))]
+pub mod schema;
+pub mod synthesis;
+pub mod systemd_boot;
+pub mod uki;
 pub mod v1;
+pub mod validation;
+pub mod versions;
 
 use std::collections::HashMap;
 use std::fmt;
+use std::fs;
 use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+pub use crate::boot_files::BootFile;
 use crate::error::{BootspecError, SynthesizeError};
-use crate::generation::Generation;
+use crate::extension::BootspecExtension;
+use crate::generation::{Generation, UnknownFields};
 
 #[doc(hidden)]
 pub(crate) type Result<T, E = BootspecError> = core::result::Result<T, E>;
 
 /// A wrapper type describing the name of a NixOS specialisation.
-#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SpecialisationName(pub String);
 
 impl fmt::Display for SpecialisationName {
@@ -31,6 +54,8 @@ pub struct SystemConfigurationRoot(pub PathBuf);
 
 /// The bootspec schema filename.
 pub const JSON_FILENAME: &str = "boot.json";
+/// The directory inside a generation that older generations placed [`JSON_FILENAME`] in.
+pub const LEGACY_JSON_DIR: &str = "bootspec";
 /// The type for a collection of generic extensions.
 pub type Extensions = HashMap<String, serde_json::Value>;
 
@@ -46,19 +71,145 @@ pub const SCHEMA_VERSION: u64 = v1::SCHEMA_VERSION;
 
 /// The current bootspec schema.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct BootJson {
     #[serde(flatten)]
     pub generation: Generation,
     #[serde(
         default = "HashMap::new",
         skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "deser::serialize_sorted",
         deserialize_with = "deser::skip_generation_fields",
         flatten
     )]
     pub extensions: Extensions,
 }
 
+/// Where a [`BootJson`] loaded from a generation came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootJsonSource {
+    /// The document was read from the file at this path.
+    File(PathBuf),
+    /// No document existed, so it was synthesized from the generation's contents.
+    Synthesized,
+}
+
 impl BootJson {
+    /// Start building a [`BootJson`].
+    pub fn builder() -> BootJsonBuilder {
+        BootJsonBuilder::default()
+    }
+
+    /// Serialize the document in canonical form: compact JSON with the keys of every object sorted.
+    ///
+    /// Equal documents always produce the same bytes, regardless of how they were built or
+    /// parsed.
+    pub fn to_canonical_json(&self) -> Result<String> {
+        // The keys are sorted explicitly, since `serde_json::Value` keeps them in insertion order
+        // if any crate in the build enables `serde_json/preserve_order`.
+        let value = serde_json::to_value(self).map_err(BootspecError::Serialize)?;
+        serde_json::to_string(&deser::Sorted(&value)).map_err(BootspecError::Serialize)
+    }
+
+    /// The SHA-256 of [`BootJson::to_canonical_json`], as a lowercase hex string.
+    ///
+    /// This is stable across runs and versions of this crate as long as the serialized form of the
+    /// document does not change, so it can be used as a cache key or to deduplicate identical
+    /// generations.
+    pub fn digest(&self) -> Result<String> {
+        let digest = Sha256::digest(self.to_canonical_json()?.as_bytes());
+        Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// Read and parse the bootspec document at `path`.
+    pub fn from_path(path: &Path) -> Result<BootJson> {
+        let contents = fs::read_to_string(path).map_err(|e| BootspecError::ReadPath {
+            path: path.to_path_buf(),
+            err: e,
+        })?;
+
+        serde_json::from_str(&contents).map_err(|e| BootspecError::Parse {
+            path: path.to_path_buf(),
+            err: e,
+        })
+    }
+
+    /// Deserialize a bootspec document.
+    ///
+    /// Unlike deserializing with [`serde`], this reports a document without a usable version as
+    /// [`BootspecError::MissingVersion`] or [`BootspecError::NoSupportedVersion`], and an invalid
+    /// generation as [`BootspecError::InvalidGeneration`].
+    pub fn from_value(document: serde_json::Value) -> Result<BootJson> {
+        Self::from_value_with(document, UnknownFields::Drop)
+    }
+
+    /// Like [`BootJson::from_value`], treating unknown fields inside the bootspec object as
+    /// `unknown_fields` says.
+    ///
+    /// Use [`UnknownFields::Preserve`] when rewriting a document that may have been written by a
+    /// newer producer, and [`UnknownFields::Deny`] to reject such documents.
+    pub fn from_value_with(
+        document: serde_json::Value,
+        unknown_fields: UnknownFields,
+    ) -> Result<BootJson> {
+        let generation = Generation::from_value_with(&document, unknown_fields)?;
+        let serde_json::Value::Object(fields) = document else {
+            return Err(BootspecError::MissingVersion);
+        };
+        // The generation's keys are skipped, so only the extensions are deserialized again.
+        let extensions = deser::skip_generation_fields(serde_json::Value::Object(fields))
+            .map_err(BootspecError::Deserialize)?;
+
+        Ok(BootJson {
+            generation,
+            extensions,
+        })
+    }
+
+    /// Read and parse the bootspec document of the generation at `generation_path`.
+    ///
+    /// The document is looked up at `$generation/boot.json`, falling back to the older
+    /// `$generation/bootspec/boot.json` location.
+    pub fn from_generation(generation_path: &Path) -> Result<(BootJson, BootJsonSource)> {
+        let path = Self::find_in_generation(generation_path)
+            .ok_or_else(|| BootspecError::MissingBootJson(generation_path.to_path_buf()))?;
+        let boot_json = Self::from_path(&path)?;
+
+        Ok((boot_json, BootJsonSource::File(path)))
+    }
+
+    /// Read and parse the bootspec document of the generation at `generation_path`, or synthesize
+    /// one using the latest specification version if the generation does not have one.
+    ///
+    /// See also [`BootJson::from_generation`] and [`BootJson::synthesize_latest`].
+    ///
+    /// ## Warnings
+    ///
+    /// A document that exists but fails to parse is an error; it does not fall back to synthesis.
+    pub fn from_generation_or_synthesize(
+        generation_path: &Path,
+    ) -> Result<(BootJson, BootJsonSource)> {
+        match Self::find_in_generation(generation_path) {
+            Some(path) => {
+                let boot_json = Self::from_path(&path)?;
+                Ok((boot_json, BootJsonSource::File(path)))
+            }
+            None => {
+                let boot_json = Self::synthesize_latest(generation_path)?;
+                Ok((boot_json, BootJsonSource::Synthesized))
+            }
+        }
+    }
+
+    fn find_in_generation(generation_path: &Path) -> Option<PathBuf> {
+        [
+            generation_path.join(JSON_FILENAME),
+            generation_path.join(LEGACY_JSON_DIR).join(JSON_FILENAME),
+        ]
+        .into_iter()
+        .find(|path| path.is_file())
+    }
+
     /// Synthesize a [`BootJson`] struct from the path to a generation using the latest
     /// specification version defined in this crate ([`SCHEMA_VERSION`]).
     ///
@@ -98,3 +249,260 @@ impl BootJson {
         })
     }
 }
+
+/// A builder for [`BootJson`], created by [`BootJson::builder`].
+///
+/// The generation is required.
+#[derive(Debug, Default)]
+pub struct BootJsonBuilder {
+    generation: Option<Generation>,
+    extensions: Extensions,
+    error: Option<BootspecError>,
+}
+
+impl BootJsonBuilder {
+    /// Set the document's generation, e.g. a [`v1::GenerationV1`].
+    pub fn generation(mut self, generation: impl Into<Generation>) -> Self {
+        self.generation = Some(generation.into());
+        self
+    }
+
+    /// Set the typed extension `T`, replacing any previous value.
+    ///
+    /// Errors (see [`BootJson::set_extension`]) are returned by [`BootJsonBuilder::build`].
+    pub fn extension<T: BootspecExtension>(mut self, extension: &T) -> Self {
+        if let Err(e) = crate::extension::set(&mut self.extensions, extension) {
+            self.error.get_or_insert(e);
+        }
+        self
+    }
+
+    /// Build the [`BootJson`], or return the first error: an invalid extension, or
+    /// [`BootspecError::MissingField`] if the generation was not set.
+    pub fn build(self) -> Result<BootJson> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+
+        Ok(BootJson {
+            generation: self
+                .generation
+                .ok_or(BootspecError::MissingField("org.nixos.bootspec.v1"))?,
+            extensions: self.extensions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{BootJson, BootJsonSource, JSON_FILENAME, LEGACY_JSON_DIR};
+    use crate::error::BootspecError;
+    use crate::extlinux::DeviceTree;
+    use crate::generation::UnknownFields;
+    use crate::v1::tests::scaffold;
+    use crate::v1::{BootSpecV1, GenerationV1};
+
+    fn scaffold_generation() -> std::path::PathBuf {
+        scaffold(
+            "x86_64-linux",
+            "test-version-1",
+            "1.1.1-test1",
+            &["loglevel=4".to_string()],
+            None,
+            false,
+        )
+    }
+
+    #[test]
+    fn from_generation_reads_boot_json() {
+        let generation = scaffold_generation();
+        let synthesized = BootJson::synthesize_latest(&generation).unwrap();
+        fs::write(
+            generation.join(JSON_FILENAME),
+            serde_json::to_string(&synthesized).unwrap(),
+        )
+        .unwrap();
+
+        let (boot_json, source) = BootJson::from_generation(&generation).unwrap();
+
+        assert_eq!(boot_json, synthesized);
+        assert_eq!(source, BootJsonSource::File(generation.join(JSON_FILENAME)));
+    }
+
+    #[test]
+    fn from_generation_reads_legacy_location() {
+        let generation = scaffold_generation();
+        let synthesized = BootJson::synthesize_latest(&generation).unwrap();
+        let legacy_path = generation.join(LEGACY_JSON_DIR).join(JSON_FILENAME);
+        fs::write(&legacy_path, serde_json::to_string(&synthesized).unwrap()).unwrap();
+
+        let (boot_json, source) = BootJson::from_generation(&generation).unwrap();
+
+        assert_eq!(boot_json, synthesized);
+        assert_eq!(source, BootJsonSource::File(legacy_path));
+    }
+
+    #[test]
+    fn from_generation_without_boot_json() {
+        let generation = scaffold_generation();
+
+        let err = BootJson::from_generation(&generation).unwrap_err();
+        assert!(matches!(err, BootspecError::MissingBootJson(path) if path == generation));
+
+        let (boot_json, source) = BootJson::from_generation_or_synthesize(&generation).unwrap();
+        assert_eq!(boot_json, BootJson::synthesize_latest(&generation).unwrap());
+        assert_eq!(source, BootJsonSource::Synthesized);
+    }
+
+    #[test]
+    fn from_generation_or_synthesize_does_not_hide_parse_errors() {
+        let generation = scaffold_generation();
+        fs::write(generation.join(JSON_FILENAME), "{}").unwrap();
+
+        let err = BootJson::from_generation_or_synthesize(&generation).unwrap_err();
+        assert!(
+            matches!(err, BootspecError::Parse { ref path, .. } if *path == generation.join(JSON_FILENAME))
+        );
+        assert!(err.to_string().contains(JSON_FILENAME));
+    }
+
+    #[test]
+    fn builder() {
+        let generation = BootJson::synthesize_latest(&scaffold_generation())
+            .unwrap()
+            .generation;
+
+        let boot_json = BootJson::builder()
+            .generation(generation.clone())
+            .extension(&DeviceTree {
+                dtbs: None,
+                name: Some("broadcom/bcm2711-rpi-4-b.dtb".into()),
+            })
+            .build()
+            .unwrap();
+        assert_eq!(boot_json.generation, generation);
+        assert!(boot_json.extension::<DeviceTree>().unwrap().is_some());
+
+        assert!(matches!(
+            BootJson::builder().build(),
+            Err(BootspecError::MissingField(_))
+        ));
+    }
+
+    #[test]
+    fn canonical_json_and_digest() {
+        let rfc_json = include_str!("../rfc0125_spec.json");
+        let boot_json: BootJson = serde_json::from_str(rfc_json).unwrap();
+        let mut reordered: BootJson = serde_json::from_str(rfc_json).unwrap();
+        for i in 0..32 {
+            reordered.extensions.insert(
+                format!("com.example.ext{}", i),
+                serde_json::json!({ "i": i }),
+            );
+        }
+        let mut other = boot_json.clone();
+        for i in (0..32).rev() {
+            other.extensions.insert(
+                format!("com.example.ext{}", i),
+                serde_json::json!({ "i": i }),
+            );
+        }
+
+        let canonical = reordered.to_canonical_json().unwrap();
+        assert!(canonical.starts_with(r#"{"com.example.ext0":{"i":0},"com.example.ext1":{"i":1},"#));
+        assert_eq!(canonical, other.to_canonical_json().unwrap());
+        assert_eq!(
+            serde_json::to_string(&reordered).unwrap(),
+            serde_json::to_string(&other).unwrap()
+        );
+
+        let digest = boot_json.digest().unwrap();
+        assert_eq!(digest.len(), 64);
+        assert_eq!(digest, boot_json.clone().digest().unwrap());
+        assert_ne!(digest, reordered.digest().unwrap());
+    }
+
+    #[test]
+    fn canonical_json_sorts_keys() {
+        let mut extension = serde_json::Map::new();
+        extension.insert("z".into(), serde_json::json!([{ "b": 2, "a": 1 }]));
+        extension.insert("a".into(), serde_json::json!(null));
+        let bootspec = BootSpecV1::builder()
+            .label("NixOS")
+            .kernel("/nix/store/xxx-linux/bzImage")
+            .init("/nix/store/xxx-nixos-system/init")
+            .kernel_param("quiet")
+            .system("x86_64-linux")
+            .toplevel("/nix/store/xxx-nixos-system")
+            .build()
+            .unwrap();
+        let mut boot_json = BootJson::builder()
+            .generation(GenerationV1::builder().bootspec(bootspec).build().unwrap())
+            .build()
+            .unwrap();
+        boot_json
+            .extensions
+            .insert("com.example.z".into(), serde_json::Value::Object(extension));
+
+        assert_eq!(
+            boot_json.to_canonical_json().unwrap(),
+            concat!(
+                r#"{"com.example.z":{"a":null,"z":[{"a":1,"b":2}]},"#,
+                r#""org.nixos.bootspec.v1":{"init":"/nix/store/xxx-nixos-system/init","#,
+                r#""initrd":null,"initrdSecrets":null,"kernel":"/nix/store/xxx-linux/bzImage","#,
+                r#""kernelParams":["quiet"],"label":"NixOS","system":"x86_64-linux","#,
+                r#""toplevel":"/nix/store/xxx-nixos-system"},"#,
+                r#""org.nixos.specialisation.v1":{}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let mut document: serde_json::Value =
+            serde_json::from_str(include_str!("../rfc0125_spec.json")).unwrap();
+        document["org.nixos.bootspec.v1"]["kernelFlavour"] = serde_json::json!("hardened");
+        document["org.nixos.specialisation.v1"]["<name>"]["org.nixos.bootspec.v1"]["future"] =
+            serde_json::json!(1);
+        document["com.example.splash.v1"] = serde_json::json!({ "image": "splash.png" });
+
+        let dropped = BootJson::from_value(document.clone()).unwrap();
+        assert_eq!(
+            dropped.extensions.keys().collect::<Vec<_>>(),
+            vec!["com.example.splash.v1"]
+        );
+        let serialized = serde_json::to_value(&dropped).unwrap();
+        assert!(serialized["org.nixos.bootspec.v1"]
+            .get("kernelFlavour")
+            .is_none());
+
+        let preserved =
+            BootJson::from_value_with(document.clone(), UnknownFields::Preserve).unwrap();
+        let serialized = serde_json::to_value(&preserved).unwrap();
+        assert_eq!(
+            serialized["org.nixos.bootspec.v1"]["kernelFlavour"],
+            "hardened"
+        );
+        assert_eq!(
+            serialized["org.nixos.specialisation.v1"]["<name>"]["org.nixos.bootspec.v1"]["future"],
+            1
+        );
+
+        let err = BootJson::from_value_with(document, UnknownFields::Deny).unwrap_err();
+        assert!(matches!(
+            err,
+            BootspecError::UnknownField(ref pointer)
+                if pointer == "/org.nixos.bootspec.v1/kernelFlavour"
+        ));
+
+        let mut null_extension: serde_json::Value =
+            serde_json::from_str(include_str!("../rfc0125_spec.json")).unwrap();
+        null_extension["com.example.splash.v1"] = serde_json::Value::Null;
+        assert!(matches!(
+            BootJson::from_value_with(null_extension, UnknownFields::Deny),
+            Err(BootspecError::Deserialize(_))
+        ));
+    }
+}
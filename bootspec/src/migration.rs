@@ -0,0 +1,172 @@
+//! Conversions between bootspec versions.
+//!
+//! Every conversion produces a [`Migrated`] value that lists the fields which could not be carried
+//! over unchanged, so that callers can decide whether a lossy conversion is acceptable.
+//!
+//! Versions are converted one step at a time. When adding version `N + 1`, implement
+//! `From<GenerationVN> for Migrated<GenerationVN+1>` (upgrades always succeed, defaulting any new
+//! required fields) and `From<GenerationVN+1> for Migrated<GenerationVN>` (downgrades drop what
+//! the older version cannot express), then extend [`Generation::into_latest`] and
+//! [`Generation::downgrade_to`] with the new step.
+use std::fmt;
+
+use crate::error::BootspecError;
+use crate::generation::Generation;
+use crate::{v1, BootSpec, Result};
+
+/// A field that could not be carried over unchanged by a conversion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldChange {
+    /// The field has no equivalent in the target version and was dropped.
+    Lost(String),
+    /// The field does not exist in the source version and was given a default value.
+    Defaulted(String),
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldChange::Lost(field) => write!(f, "{} was lost", field),
+            FieldChange::Defaulted(field) => write!(f, "{} was defaulted", field),
+        }
+    }
+}
+
+/// The result of converting a generation to another version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migrated<T> {
+    /// The converted generation.
+    pub generation: T,
+    /// The fields that were lost or defaulted, in the order the conversion steps ran.
+    pub changes: Vec<FieldChange>,
+}
+
+impl<T> Migrated<T> {
+    /// A conversion that carried every field over unchanged.
+    pub fn lossless(generation: T) -> Self {
+        Self {
+            generation,
+            changes: Vec::new(),
+        }
+    }
+
+    /// Whether no field was dropped. Defaulted fields do not lose information.
+    pub fn is_lossless(&self) -> bool {
+        !self
+            .changes
+            .iter()
+            .any(|change| matches!(change, FieldChange::Lost(_)))
+    }
+
+    /// Convert the generation, keeping the changes made so far.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Migrated<U> {
+        Migrated {
+            generation: f(self.generation),
+            changes: self.changes,
+        }
+    }
+
+    /// Run another conversion step, accumulating its changes after the ones made so far.
+    pub fn and_then<U>(mut self, f: impl FnOnce(T) -> Migrated<U>) -> Migrated<U> {
+        let mut next = f(self.generation);
+        self.changes.append(&mut next.changes);
+        Migrated {
+            generation: next.generation,
+            changes: self.changes,
+        }
+    }
+}
+
+impl Generation {
+    /// Convert to the latest version this crate supports ([`crate::SCHEMA_VERSION`]).
+    pub fn into_latest(self) -> Migrated<BootSpec> {
+        match self {
+            Generation::V1(generation) => Migrated::lossless(generation),
+        }
+    }
+
+    /// Convert to the older (or same) `version`.
+    ///
+    /// Returns [`BootspecError::UnsupportedMigration`] if `version` is newer than this
+    /// generation or is not supported by this crate.
+    pub fn downgrade_to(self, version: u64) -> Result<Migrated<Generation>> {
+        let from = self.version();
+        if version > from {
+            return Err(BootspecError::UnsupportedMigration { from, to: version });
+        }
+
+        match (self, version) {
+            (Generation::V1(generation), v1::SCHEMA_VERSION) => {
+                Ok(Migrated::lossless(Generation::V1(generation)))
+            }
+            (_, to) => Err(BootspecError::UnsupportedMigration { from, to }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{FieldChange, Migrated};
+    use crate::error::BootspecError;
+    use crate::generation::Generation;
+    use crate::v1::GenerationV1;
+
+    fn generation() -> Generation {
+        serde_json::from_str(include_str!("../rfc0125_spec.json")).unwrap()
+    }
+
+    #[test]
+    fn v1_is_latest() {
+        let migrated = generation().into_latest();
+        assert!(migrated.is_lossless());
+        assert!(migrated.changes.is_empty());
+
+        let downgraded = generation().downgrade_to(1).unwrap();
+        assert_eq!(downgraded, Migrated::lossless(generation()));
+
+        let GenerationV1 { bootspec, .. } = generation().try_into().unwrap();
+        assert_eq!(bootspec, migrated.generation.bootspec);
+    }
+
+    #[test]
+    fn unsupported_targets() {
+        assert!(matches!(
+            generation().downgrade_to(2),
+            Err(BootspecError::UnsupportedMigration { from: 1, to: 2 })
+        ));
+        assert!(matches!(
+            generation().downgrade_to(0),
+            Err(BootspecError::UnsupportedMigration { from: 1, to: 0 })
+        ));
+    }
+
+    #[test]
+    fn changes_accumulate() {
+        let migrated = Migrated {
+            generation: PathBuf::from("/nix/store/xxx-linux/bzImage"),
+            changes: vec![FieldChange::Defaulted(
+                "org.nixos.bootspec.v2/cmdline".into(),
+            )],
+        }
+        .and_then(|kernel| Migrated {
+            generation: kernel.display().to_string(),
+            changes: vec![FieldChange::Lost("org.nixos.bootspec.v2/cmdline".into())],
+        });
+
+        assert_eq!(migrated.generation, "/nix/store/xxx-linux/bzImage");
+        assert!(!migrated.is_lossless());
+        assert_eq!(
+            migrated
+                .changes
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "org.nixos.bootspec.v2/cmdline was defaulted",
+                "org.nixos.bootspec.v2/cmdline was lost",
+            ]
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Enumerate the generations of a NixOS system profile.
+//!
+//! A profile such as `/nix/var/nix/profiles/system` is a symlink to one of its generations, each of
+//! which is a sibling symlink named `system-N-link` pointing at a system configuration root.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::error::BootspecError;
+use crate::{BootJson, BootJsonSource, Result};
+
+/// A single generation of a profile.
+#[derive(Debug, Clone)]
+pub struct ProfileGeneration {
+    /// The generation number, e.g. `42` for `system-42-link`.
+    pub number: u64,
+    /// The path to the `system-N-link` symlink.
+    pub link: PathBuf,
+    /// The modification time of the symlink itself, i.e. when the generation was created.
+    pub modified: SystemTime,
+    /// The fully resolved system configuration root the link points to.
+    pub toplevel: PathBuf,
+    /// The generation's bootspec document.
+    pub boot_json: BootJson,
+    /// Whether `boot_json` was read from the generation or synthesized.
+    pub source: BootJsonSource,
+}
+
+/// A generation link that could not be turned into a [`ProfileGeneration`], e.g. because it is
+/// dangling or its bootspec document is invalid.
+#[derive(Debug)]
+pub struct BrokenGeneration {
+    /// The generation number, e.g. `42` for `system-42-link`.
+    pub number: u64,
+    /// The path to the `system-N-link` symlink.
+    pub link: PathBuf,
+    /// Why the generation could not be loaded.
+    pub error: BootspecError,
+}
+
+/// All generations of a profile, sorted by generation number.
+#[derive(Debug)]
+pub struct ProfileGenerations {
+    /// The generations that were loaded successfully.
+    pub generations: Vec<ProfileGeneration>,
+    /// The generations that could not be loaded.
+    pub broken: Vec<BrokenGeneration>,
+    /// The number of the generation the profile currently points to, if it could be determined.
+    pub current: Option<u64>,
+}
+
+impl ProfileGenerations {
+    /// The generation the profile currently points to, if it was loaded successfully.
+    pub fn current(&self) -> Option<&ProfileGeneration> {
+        let current = self.current?;
+        self.generations.iter().find(|g| g.number == current)
+    }
+}
+
+/// Enumerate every generation of the profile at `profile`, e.g. `/nix/var/nix/profiles/system`.
+///
+/// Each generation's bootspec document is loaded with [`BootJson::from_generation_or_synthesize`].
+/// Generations that fail to load are reported in [`ProfileGenerations::broken`] rather than
+/// aborting the enumeration.
+pub fn generations(profile: &Path) -> Result<ProfileGenerations> {
+    let name = profile
+        .file_name()
+        .ok_or(BootspecError::InvalidFileName(profile.to_path_buf()))?
+        .to_str()
+        .ok_or(BootspecError::InvalidUtf8(profile.to_path_buf()))?;
+    let profiles_dir = match profile.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut generations = Vec::new();
+    let mut broken = Vec::new();
+
+    let entries = fs::read_dir(profiles_dir).map_err(|e| BootspecError::ReadPath {
+        path: profiles_dir.to_path_buf(),
+        err: e,
+    })?;
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(number) = file_name
+            .to_str()
+            .and_then(|file_name| parse_generation_number(name, file_name))
+        else {
+            continue;
+        };
+
+        let link = entry.path();
+        match load_generation(number, &link) {
+            Ok(generation) => generations.push(generation),
+            Err(error) => broken.push(BrokenGeneration {
+                number,
+                link,
+                error,
+            }),
+        }
+    }
+
+    generations.sort_by_key(|g| g.number);
+    broken.sort_by_key(|g| g.number);
+
+    let current = fs::read_link(profile).ok().and_then(|target| {
+        target
+            .file_name()?
+            .to_str()
+            .and_then(|file_name| parse_generation_number(name, file_name))
+    });
+
+    Ok(ProfileGenerations {
+        generations,
+        broken,
+        current,
+    })
+}
+
+/// Parse the generation number out of a link named `{profile_name}-N-link`.
+fn parse_generation_number(profile_name: &str, file_name: &str) -> Option<u64> {
+    file_name
+        .strip_prefix(profile_name)?
+        .strip_prefix('-')?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+fn load_generation(number: u64, link: &Path) -> Result<ProfileGeneration> {
+    let modified = fs::symlink_metadata(link)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| BootspecError::ReadPath {
+            path: link.to_path_buf(),
+            err: e,
+        })?;
+    let toplevel = fs::canonicalize(link).map_err(|e| BootspecError::ReadPath {
+        path: link.to_path_buf(),
+        err: e,
+    })?;
+    let (boot_json, source) = BootJson::from_generation_or_synthesize(&toplevel)?;
+
+    Ok(ProfileGeneration {
+        number,
+        link: link.to_path_buf(),
+        modified,
+        toplevel,
+        boot_json,
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use super::{generations, parse_generation_number};
+    use crate::error::BootspecError;
+    use crate::v1::tests::scaffold;
+    use crate::{BootJson, BootJsonSource, JSON_FILENAME};
+
+    fn scaffold_generation(kernel_version: &str) -> PathBuf {
+        scaffold(
+            "x86_64-linux",
+            "test-version",
+            kernel_version,
+            &["loglevel=4".to_string()],
+            None,
+            false,
+        )
+    }
+
+    #[test]
+    fn generation_numbers() {
+        assert_eq!(parse_generation_number("system", "system-1-link"), Some(1));
+        assert_eq!(
+            parse_generation_number("system", "system-420-link"),
+            Some(420)
+        );
+        assert_eq!(parse_generation_number("system", "system"), None);
+        assert_eq!(parse_generation_number("system", "system-link"), None);
+        assert_eq!(parse_generation_number("system", "system-x-link"), None);
+        assert_eq!(parse_generation_number("system", "other-1-link"), None);
+        assert_eq!(parse_generation_number("system", "system-profiles"), None);
+    }
+
+    #[test]
+    fn enumerate_profile() {
+        let profiles = TempDir::new().unwrap();
+        let profile = profiles.path().join("system");
+
+        let first = scaffold_generation("1.1.1-test1");
+        let second = scaffold_generation("1.1.1-test2");
+        let boot_json = BootJson::synthesize_latest(&second).unwrap();
+        fs::write(
+            second.join(JSON_FILENAME),
+            serde_json::to_string(&boot_json).unwrap(),
+        )
+        .unwrap();
+
+        symlink(&first, profiles.path().join("system-1-link")).unwrap();
+        symlink(&second, profiles.path().join("system-10-link")).unwrap();
+        symlink(
+            profiles.path().join("missing"),
+            profiles.path().join("system-3-link"),
+        )
+        .unwrap();
+        symlink(&first, profiles.path().join("other-2-link")).unwrap();
+        symlink("system-10-link", &profile).unwrap();
+
+        let found = generations(&profile).unwrap();
+
+        assert_eq!(
+            found
+                .generations
+                .iter()
+                .map(|g| g.number)
+                .collect::<Vec<_>>(),
+            vec![1, 10]
+        );
+        assert_eq!(found.generations[0].toplevel, first.canonicalize().unwrap());
+        assert_eq!(found.generations[0].source, BootJsonSource::Synthesized);
+        assert_eq!(
+            found.generations[1].source,
+            BootJsonSource::File(second.canonicalize().unwrap().join(JSON_FILENAME))
+        );
+        assert_eq!(found.generations[1].boot_json, boot_json);
+
+        assert_eq!(found.broken.len(), 1);
+        assert_eq!(found.broken[0].number, 3);
+        assert!(matches!(
+            found.broken[0].error,
+            BootspecError::ReadPath { .. }
+        ));
+
+        assert_eq!(found.current, Some(10));
+        assert_eq!(found.current().unwrap().number, 10);
+    }
+}
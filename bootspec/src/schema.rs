@@ -0,0 +1,304 @@
+//! The bootspec JSON Schema (`schema.json`), and validation of raw documents against it.
+//!
+//! With the `schemars` feature, [`generate`] derives the schema from the Rust types, and a test
+//! checks that the checked-in `schema.json` matches it.
+//!
+//! Deserializing into [`crate::BootJson`] is more lenient than the schema in some places (e.g. it does
+//! not enforce the `KernelParameter` pattern). With the `json-schema` feature, `validate` checks
+//! the raw [`serde_json::Value`] first to catch those differences.
+#[cfg(feature = "schemars")]
+use std::borrow::Cow;
+#[cfg(feature = "json-schema")]
+use std::sync::OnceLock;
+
+#[cfg(feature = "schemars")]
+use schemars::generate::SchemaSettings;
+#[cfg(feature = "schemars")]
+use schemars::transform::RemoveRefSiblings;
+#[cfg(feature = "schemars")]
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+#[cfg(feature = "schemars")]
+use serde_json::{json, Value};
+
+#[cfg(feature = "json-schema")]
+use crate::error::BootspecError;
+#[cfg(feature = "json-schema")]
+use crate::validation::{Severity, ValidationReport};
+#[cfg(feature = "json-schema")]
+use crate::{BootJson, Result};
+
+/// The bootspec JSON Schema, as shipped in the repository's `schema.json`.
+pub const SCHEMA_JSON: &str = include_str!("../schema.json");
+
+/// The `$id` of the bootspec JSON Schema.
+pub const SCHEMA_ID: &str =
+    "https://raw.githubusercontent.com/DeterminateSystems/bootspec/main/schema.json";
+
+/// Generate the JSON Schema of a [`crate::BootJson`] document.
+///
+/// The schema of `org.nixos.bootspec.v1` is derived from [`crate::v1::BootSpecV1`]. The
+/// surrounding document is described here, since [`crate::BootJson`] flattens the generation and
+/// its extensions into the top-level object (and into each specialisation), which the derived
+/// schema cannot express.
+#[cfg(feature = "schemars")]
+pub fn generate() -> Value {
+    // Keep the descriptions of paths next to their `$ref`, as `allOf: [{ "$ref": ... }]`.
+    let mut generator = SchemaSettings::draft2020_12()
+        .with_transform(RemoveRefSiblings::default())
+        .into_generator();
+    let bootspec = generator.subschema_for::<crate::v1::BootSpecV1>();
+
+    json!({
+        "$id": SCHEMA_ID,
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": format!("NixOS bootspec v{} schema", crate::v1::SCHEMA_VERSION),
+        "description": "Bootspec is a set of memoized facts about a system's closure. The top-level object may contain arbitrary further keys (\"extensions\") whose semantics may be defined by third parties. The use of reverse-domain-name namespacing is recommended in order to avoid name collisions.",
+        "type": "object",
+        "required": ["org.nixos.bootspec.v1"],
+        "properties": {
+            "org.nixos.bootspec.v1": bootspec,
+            "org.nixos.specialisation.v1": {
+                "type": "object",
+                "patternProperties": {
+                    "^.*$": {
+                        "type": "object",
+                        "properties": {
+                            "org.nixos.bootspec.v1": bootspec,
+                        },
+                        "required": ["org.nixos.bootspec.v1"],
+                        "additionalProperties": true,
+                    },
+                },
+            },
+        },
+        "patternProperties": {
+            "^.*$": {
+                "description": "Additional top-level specialisations",
+            },
+        },
+        "$defs": generator.take_definitions(true),
+    })
+}
+
+/// The example of `kernelParams`.
+#[cfg(feature = "schemars")]
+pub(crate) const KERNEL_PARAMS_EXAMPLE: &[&str] = &[
+    "amd_iommu=on",
+    "amd_iommu=pt",
+    "iommu=pt",
+    "kvm.ignore_msrs=1",
+    "kvm.report_ignored_msrs=0",
+    "udev.log_priority=3",
+    "systemd.unified_cgroup_hierarchy=1",
+    "loglevel=4",
+];
+
+/// The schema of the paths in a bootspec, for `#[schemars(with = "NixStorePath")]`.
+#[cfg(feature = "schemars")]
+pub(crate) struct NixStorePath;
+
+#[cfg(feature = "schemars")]
+impl JsonSchema for NixStorePath {
+    fn schema_name() -> Cow<'static, str> {
+        "NixStorePath".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "description": "A valid Nix store path",
+        })
+    }
+}
+
+#[cfg(feature = "json-schema")]
+fn validator() -> &'static jsonschema::Validator {
+    static VALIDATOR: OnceLock<jsonschema::Validator> = OnceLock::new();
+
+    VALIDATOR.get_or_init(|| {
+        let schema: serde_json::Value =
+            serde_json::from_str(SCHEMA_JSON).expect("the embedded schema is valid JSON");
+        jsonschema::validator_for(&schema).expect("the embedded schema is a valid JSON Schema")
+    })
+}
+
+/// Validate a raw document against the embedded schema.
+///
+/// Every violation is reported as an error, with a JSON pointer to the offending value.
+#[cfg(feature = "json-schema")]
+pub fn validate(document: &serde_json::Value) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    for error in validator().iter_errors(document) {
+        report.push(
+            Severity::Error,
+            error.instance_path().to_string(),
+            error.to_string(),
+        );
+    }
+
+    report
+}
+
+#[cfg(feature = "json-schema")]
+impl BootJson {
+    /// Deserialize a [`BootJson`] from a raw document, after checking it against the embedded
+    /// schema.
+    ///
+    /// Returns [`BootspecError::Schema`] if the document violates the schema.
+    pub fn from_value_with_schema(document: serde_json::Value) -> Result<BootJson> {
+        let report = validate(&document);
+        if !report.is_valid() {
+            return Err(BootspecError::Schema(report));
+        }
+
+        BootJson::from_value(document)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "schemars")]
+    use std::collections::BTreeSet;
+    #[cfg(feature = "json-schema")]
+    use std::fs;
+    #[cfg(feature = "json-schema")]
+    use std::path::Path;
+    #[cfg(feature = "schemars")]
+    use std::path::PathBuf;
+
+    #[cfg(feature = "json-schema")]
+    use super::validate;
+    #[cfg(feature = "schemars")]
+    use super::{generate, SCHEMA_JSON};
+    #[cfg(feature = "json-schema")]
+    use crate::error::BootspecError;
+    #[cfg(feature = "schemars")]
+    use crate::v1::BootSpecV1;
+    #[cfg(feature = "json-schema")]
+    use crate::BootJson;
+    #[cfg(feature = "schemars")]
+    use crate::SystemConfigurationRoot;
+
+    #[test]
+    #[cfg(feature = "schemars")]
+    fn schema_json_is_up_to_date() {
+        let generated = generate();
+        let checked_in: serde_json::Value = serde_json::from_str(SCHEMA_JSON).unwrap();
+        assert!(
+            checked_in == generated,
+            "schema.json is out of date, update it to match the generated schema:\n{}",
+            serde_json::to_string_pretty(&generated).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "schemars")]
+    fn schema_matches_serialized_fields() {
+        let bootspec = serde_json::to_value(BootSpecV1 {
+            label: String::new(),
+            kernel: PathBuf::new(),
+            kernel_params: Default::default(),
+            init: PathBuf::new(),
+            initrd: None,
+            initrd_secrets: None,
+            system: String::new(),
+            toplevel: SystemConfigurationRoot(PathBuf::new()),
+            unknown_fields: Default::default(),
+        })
+        .unwrap();
+        let bootspec = bootspec.as_object().unwrap();
+
+        let schema = &generate()["$defs"]["BootspecV1"];
+        let required = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key.as_str().unwrap())
+            .collect::<BTreeSet<_>>();
+        let non_null = bootspec
+            .iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, _)| key.as_str())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(non_null, required);
+
+        let properties = schema["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            bootspec.keys().map(String::as_str).collect::<BTreeSet<_>>(),
+            properties
+        );
+        assert_eq!(
+            BootSpecV1::FIELDS.iter().copied().collect::<BTreeSet<_>>(),
+            properties
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "json-schema")]
+    fn expected_synthesis_fixtures() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../synthesize/integration-test-cases/expected-synthesis");
+
+        let mut checked = 0;
+        for entry in fs::read_dir(&fixtures).unwrap() {
+            let path = entry.unwrap().path();
+            let contents = fs::read_to_string(&path).unwrap();
+            let document: serde_json::Value = serde_json::from_str(&contents).unwrap();
+
+            let report = validate(&document);
+            assert!(report.is_valid(), "{}: {}", path.display(), report);
+            serde_json::from_str::<BootJson>(&contents)
+                .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            BootJson::from_value_with_schema(document).unwrap();
+
+            checked += 1;
+        }
+
+        assert!(checked > 0);
+    }
+
+    #[test]
+    #[cfg(feature = "json-schema")]
+    fn rfc0125_example() {
+        let document = serde_json::from_str(include_str!("../rfc0125_spec.json")).unwrap();
+        assert!(validate(&document).is_valid());
+    }
+
+    #[test]
+    #[cfg(feature = "json-schema")]
+    fn violations_are_reported_before_deserialization() {
+        let document = serde_json::json!({
+            "org.nixos.bootspec.v1": {
+                "init": "/nix/store/xxx-nixos-system-xxx/init",
+                "kernel": "/nix/store/xxx-linux/bzImage",
+                "kernelParams": ["loglevel=4", "foo=bar baz"],
+                "label": "NixOS 21.11 (Linux 5.15.30)",
+                "system": "x86_64-linux",
+                "toplevel": "/nix/store/xxx-nixos-system-xxx"
+            }
+        });
+
+        // The typed path alone accepts the document...
+        serde_json::from_value::<BootJson>(document.clone()).unwrap();
+
+        // ...but the schema does not.
+        let report = validate(&document);
+        assert_eq!(report.diagnostics.len(), 1);
+        assert_eq!(
+            report.diagnostics[0].pointer,
+            "/org.nixos.bootspec.v1/kernelParams/1"
+        );
+        assert!(matches!(
+            BootJson::from_value_with_schema(document),
+            Err(BootspecError::Schema(_))
+        ));
+
+        let missing_kernel = serde_json::json!({ "org.nixos.bootspec.v1": {} });
+        assert!(!validate(&missing_kernel).is_valid());
+    }
+}
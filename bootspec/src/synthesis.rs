@@ -0,0 +1,323 @@
+//! Reporting on the choices made while synthesizing a bootspec document.
+//!
+//! Synthesis has to guess at some things a real `boot.json` states explicitly, e.g. which kernel
+//! modules directory belongs to the kernel. [`BootJson::synthesize_with_report`] returns a
+//! [`SynthesisWarning`] for every such guess, so that callers can surface them or refuse to use
+//! the result.
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{BootspecError, SynthesizeError};
+use crate::generation::Generation;
+use crate::{kernel, v1, BootJson, Result};
+
+/// Something that synthesis had to guess at or work around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SynthesisWarning {
+    /// The generation (or specialisation) the warning is about.
+    pub generation: PathBuf,
+    /// What happened.
+    pub kind: SynthesisWarningKind,
+}
+
+/// The kinds of [`SynthesisWarning`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SynthesisWarningKind {
+    /// `kernel-modules/lib/modules` contains more than one directory. The kernel version in the
+    /// label was taken from `chosen`, the one matching the version embedded in the kernel image.
+    MultipleKernelModuleDirs {
+        /// The directory name the kernel version was taken from.
+        chosen: String,
+        /// Every directory name that was found, sorted.
+        candidates: Vec<String>,
+    },
+    /// The generation has no `initrd`, so `initrd` was left unset.
+    NoInitrd,
+    /// `kernel-params` contains an unterminated double quote, so its last parameter extends to
+    /// the end of the file.
+    UnterminatedQuote,
+    /// `kernel-params` gives these keys more than once. The last occurrence is the effective one.
+    DuplicateKernelParams(Vec<String>),
+}
+
+impl fmt::Display for SynthesisWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.generation.display(), self.kind)
+    }
+}
+
+impl fmt::Display for SynthesisWarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SynthesisWarningKind::MultipleKernelModuleDirs { chosen, candidates } => write!(
+                f,
+                "multiple kernel module dirs found ({}), using {}",
+                candidates.join(", "),
+                chosen
+            ),
+            SynthesisWarningKind::NoInitrd => write!(f, "no initrd"),
+            SynthesisWarningKind::UnterminatedQuote => {
+                write!(f, "unterminated quote in kernel-params")
+            }
+            SynthesisWarningKind::DuplicateKernelParams(keys) => {
+                write!(f, "duplicate kernel params: {}", keys.join(", "))
+            }
+        }
+    }
+}
+
+/// A synthesized [`BootJson`] and the warnings raised while synthesizing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SynthesisReport {
+    /// The synthesized document.
+    pub boot_json: BootJson,
+    /// The warnings, in the order they were raised: a generation's own warnings come before those
+    /// of its specialisations.
+    pub warnings: Vec<SynthesisWarning>,
+}
+
+impl SynthesisReport {
+    /// Whether synthesis did not have to guess at anything.
+    pub fn is_clean(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+impl BootJson {
+    /// Synthesize a [`BootJson`] like [`BootJson::synthesize_version`], additionally reporting
+    /// every guess that was made along the way.
+    ///
+    /// Problems that make synthesis impossible are still returned as errors.
+    pub fn synthesize_with_report(generation_path: &Path, version: u64) -> Result<SynthesisReport> {
+        let mut warnings = Vec::new();
+        let generation = match version {
+            v1::SCHEMA_VERSION => Generation::V1(v1::GenerationV1::synthesize_reporting(
+                generation_path,
+                &mut warnings,
+            )?),
+            v => {
+                return Err(BootspecError::Synthesize(
+                    SynthesizeError::UnsupportedVersion(v),
+                ))
+            }
+        };
+
+        Ok(SynthesisReport {
+            boot_json: BootJson {
+                generation,
+                extensions: Default::default(),
+            },
+            warnings,
+        })
+    }
+}
+
+/// Collects the warnings of one generation.
+pub(crate) struct Warnings<'a> {
+    generation: &'a Path,
+    warnings: &'a mut Vec<SynthesisWarning>,
+}
+
+impl<'a> Warnings<'a> {
+    pub(crate) fn new(generation: &'a Path, warnings: &'a mut Vec<SynthesisWarning>) -> Self {
+        Self {
+            generation,
+            warnings,
+        }
+    }
+
+    pub(crate) fn push(&mut self, kind: SynthesisWarningKind) {
+        self.warnings.push(SynthesisWarning {
+            generation: self.generation.to_path_buf(),
+            kind,
+        });
+    }
+}
+
+/// The version embedded in the kernel image at `path`, or `None` if it cannot be found.
+pub(crate) fn kernel_image_version(path: &Path) -> Result<Option<String>> {
+    match kernel::inspect(path) {
+        Ok(image) => Ok(image.version),
+        Err(BootspecError::Kernel(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::SynthesisWarningKind;
+    use crate::error::{BootspecError, SynthesizeError};
+    use crate::v1::tests::scaffold;
+    use crate::BootJson;
+
+    fn arm64_image(version: &str) -> Vec<u8> {
+        let mut image = vec![0; 0x40];
+        image[0x38..0x3c].copy_from_slice(b"ARM\x64");
+        image.extend_from_slice(
+            format!(
+                "Linux version {} (nixbld@localhost) #1-NixOS SMP\0",
+                version
+            )
+            .as_bytes(),
+        );
+        image
+    }
+
+    #[test]
+    fn clean_generation() {
+        let generation = scaffold(
+            "x86_64-linux",
+            "test-version-1",
+            "1.1.1-test1",
+            &["loglevel=4".to_string()],
+            Some(vec!["dark"]),
+            false,
+        );
+
+        let report = BootJson::synthesize_with_report(&generation, 1).unwrap();
+        assert!(report.is_clean(), "{:?}", report.warnings);
+        assert_eq!(
+            report.boot_json,
+            BootJson::synthesize_latest(&generation).unwrap()
+        );
+
+        assert!(matches!(
+            BootJson::synthesize_with_report(&generation, 2),
+            Err(BootspecError::Synthesize(
+                SynthesizeError::UnsupportedVersion(2)
+            ))
+        ));
+    }
+
+    #[test]
+    fn reports_guesses() {
+        let generation = scaffold(
+            "x86_64-linux",
+            "test-version-1",
+            "1.1.1-test1",
+            &[
+                "loglevel=4".to_string(),
+                "quiet".to_string(),
+                "loglevel=7".to_string(),
+                "root=\"/dev/disk/by-label/nix os".to_string(),
+            ],
+            Some(vec!["dark"]),
+            false,
+        );
+        let generation = generation.canonicalize().unwrap();
+        fs::create_dir(generation.join("kernel-modules/lib/modules/1.1.1-test0")).unwrap();
+        fs::write(generation.join("kernel"), arm64_image("1.1.1-test1")).unwrap();
+        let dark = generation.join("specialisation/dark");
+        fs::remove_file(dark.join("initrd")).unwrap();
+
+        let report = BootJson::synthesize_with_report(&generation, 1).unwrap();
+        let warnings = report
+            .warnings
+            .iter()
+            .map(|warning| (warning.generation.clone(), warning.kind.clone()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            warnings[0],
+            (
+                generation.clone(),
+                SynthesisWarningKind::MultipleKernelModuleDirs {
+                    chosen: "1.1.1-test1".into(),
+                    candidates: vec!["1.1.1-test0".into(), "1.1.1-test1".into()],
+                }
+            )
+        );
+        assert_eq!(
+            warnings[1..],
+            [
+                (generation.clone(), SynthesisWarningKind::UnterminatedQuote),
+                (
+                    generation.clone(),
+                    SynthesisWarningKind::DuplicateKernelParams(vec!["loglevel".into()])
+                ),
+                (dark.clone(), SynthesisWarningKind::UnterminatedQuote),
+                (
+                    dark.clone(),
+                    SynthesisWarningKind::DuplicateKernelParams(vec!["loglevel".into()])
+                ),
+                (dark.clone(), SynthesisWarningKind::NoInitrd),
+            ]
+        );
+        assert_eq!(
+            report.warnings[5].to_string(),
+            format!("{}: no initrd", dark.display())
+        );
+    }
+
+    #[test]
+    fn ambiguous_kernel_version() {
+        let generation = scaffold(
+            "x86_64-linux",
+            "test-version-1",
+            "1.1.1-test1",
+            &[],
+            None,
+            false,
+        );
+        let modules = generation.join("kernel-modules/lib/modules");
+        fs::create_dir(modules.join("1.1.1-test2")).unwrap();
+
+        let err = BootJson::synthesize_with_report(&generation, 1).unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                BootspecError::Synthesize(SynthesizeError::AmbiguousKernelVersion {
+                    image_version: None,
+                    candidates,
+                    ..
+                }) if candidates == &["1.1.1-test1", "1.1.1-test2"]
+            ),
+            "{:?}",
+            err
+        );
+
+        // A version matching neither directory does not help either.
+        fs::write(generation.join("kernel"), arm64_image("1.1.1-test3")).unwrap();
+        assert!(matches!(
+            BootJson::synthesize_latest(&generation),
+            Err(BootspecError::Synthesize(
+                SynthesizeError::AmbiguousKernelVersion {
+                    image_version: Some(_),
+                    ..
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn kernel_version_without_kernel_modules() {
+        let generation = scaffold(
+            "aarch64-linux",
+            "test-version-1",
+            "1.1.1-test1",
+            &[],
+            None,
+            false,
+        );
+        fs::remove_dir_all(generation.join("kernel-modules")).unwrap();
+
+        assert!(matches!(
+            BootJson::synthesize_latest(&generation),
+            Err(BootspecError::Synthesize(
+                SynthesizeError::MissingKernelVersionDir(_)
+            ))
+        ));
+
+        fs::write(generation.join("kernel"), arm64_image("6.6.8")).unwrap();
+        let report = BootJson::synthesize_with_report(&generation, 1).unwrap();
+        assert!(report.is_clean(), "{:?}", report.warnings);
+        let crate::generation::Generation::V1(generation) = &report.boot_json.generation;
+        assert_eq!(
+            generation.bootspec.label,
+            "NixOS test-version-1 (Linux 6.6.8)"
+        );
+    }
+}
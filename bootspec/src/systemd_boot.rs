@@ -0,0 +1,319 @@
+//! Generate systemd-boot entries following the Boot Loader Specification (Type 1).
+//!
+//! See: <https://uapi-group.org/specifications/specs/boot_loader_specification/>
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::boot_files::{self, BootFile, BootFiles};
+use crate::generation::Generation;
+use crate::v1::BootSpecV1;
+use crate::{BootJson, Result, SpecialisationName};
+
+/// Settings that control how entries are named and where their files are placed on the ESP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The ESP-relative directory kernels and initrds are copied to.
+    pub esp_dir: PathBuf,
+    /// The prefix of every entry's file name, e.g. `nixos` for `nixos-generation-1.conf`.
+    pub entry_prefix: String,
+    /// The `sort-key` of every entry.
+    pub sort_key: String,
+    /// The initrds returned by [`BootSpecV1::append_initrd_secrets`], by the
+    /// [`BootSpecV1::initrd_secrets`] tool they were created with.
+    pub initrds_with_secrets: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            esp_dir: PathBuf::from("/EFI/nixos"),
+            entry_prefix: String::from("nixos"),
+            sort_key: String::from("nixos"),
+            initrds_with_secrets: BTreeMap::new(),
+        }
+    }
+}
+
+/// A single Boot Loader Specification Type 1 entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The entry identifier; the entry must be written to `loader/entries/$id.conf`.
+    pub id: String,
+    /// The human-readable title, from [`BootSpecV1::label`].
+    pub title: String,
+    /// The version, used to order entries with the same sort key.
+    pub version: String,
+    /// The sort key, used to group entries.
+    pub sort_key: String,
+    /// The ESP-relative path to the kernel.
+    pub linux: PathBuf,
+    /// The ESP-relative path to the initrd.
+    pub initrd: Option<PathBuf>,
+    /// The kernel command line.
+    pub options: String,
+}
+
+impl Entry {
+    /// The file name of the entry inside `loader/entries`.
+    pub fn file_name(&self) -> String {
+        format!("{}.conf", self.id)
+    }
+
+    /// Render the contents of the entry's `.conf` file.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // Writing to a `String` cannot fail.
+        let _ = writeln!(out, "title {}", self.title);
+        let _ = writeln!(out, "version {}", self.version);
+        let _ = writeln!(out, "sort-key {}", self.sort_key);
+        let _ = writeln!(out, "linux {}", self.linux.display());
+        if let Some(initrd) = &self.initrd {
+            let _ = writeln!(out, "initrd {}", initrd.display());
+        }
+        let _ = writeln!(out, "options {}", self.options);
+        out
+    }
+}
+
+/// Everything needed to make a generation bootable with systemd-boot.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entries {
+    /// The generation's entry, followed by one entry per specialisation sorted by name.
+    pub entries: Vec<Entry>,
+    /// The kernels and initrds referenced by `entries`, without duplicates. Destinations are
+    /// relative to the root of the ESP.
+    pub files: Vec<BootFile>,
+}
+
+/// Build the entries for generation number `generation` described by `boot_json`, including
+/// one entry per specialisation. Specialisation names become part of the entries' file names, so
+/// they may only contain ASCII letters, digits, `-` and `_`.
+pub fn entries(generation: u64, boot_json: &BootJson, config: &Config) -> Result<Entries> {
+    let Generation::V1(generation_v1) = &boot_json.generation;
+
+    let mut files = BootFiles::default();
+    let id = format!("{}-generation-{}", config.entry_prefix, generation);
+    let mut entries = vec![entry(
+        id.clone(),
+        generation,
+        None,
+        &generation_v1.bootspec,
+        config,
+        &mut files,
+    )?];
+
+    let mut specialisations = generation_v1.specialisations.iter().collect::<Vec<_>>();
+    specialisations.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+    for (name, specialisation) in specialisations {
+        entries.push(entry(
+            format!("{}-specialisation-{}", id, boot_files::entry_name(name)?),
+            generation,
+            Some(name),
+            &specialisation.generation.bootspec,
+            config,
+            &mut files,
+        )?);
+    }
+
+    Ok(Entries {
+        entries,
+        files: files.0,
+    })
+}
+
+fn entry(
+    id: String,
+    generation: u64,
+    specialisation: Option<&SpecialisationName>,
+    bootspec: &BootSpecV1,
+    config: &Config,
+    files: &mut BootFiles,
+) -> Result<Entry> {
+    let linux = files.copy(&bootspec.kernel, &config.esp_dir, ".efi")?;
+    let initrd = files.copy_initrd(
+        bootspec,
+        &config.initrds_with_secrets,
+        &config.esp_dir,
+        ".efi",
+    )?;
+
+    let title = match specialisation {
+        Some(name) => format!("{} ({})", bootspec.label, name),
+        None => bootspec.label.clone(),
+    };
+
+    Ok(Entry {
+        id,
+        title,
+        version: format!("Generation {}", generation),
+        sort_key: config.sort_key.clone(),
+        linux,
+        initrd,
+        options: bootspec.kernel_command_line(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{entries, Config};
+    use crate::boot_files::BootFile;
+    use crate::error::BootspecError;
+    use crate::BootJson;
+
+    const JSON: &str = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/xxx-nixos-system-xxx/init",
+        "initrd": "/nix/store/xxx-initrd-linux/initrd",
+        "kernel": "/nix/store/xxx-linux/bzImage",
+        "kernelParams": ["loglevel=4", "net.ifnames=0"],
+        "label": "NixOS 21.11.20210810.dirty (Linux 5.15.30)",
+        "system": "x86_64-linux",
+        "toplevel": "/nix/store/xxx-nixos-system-xxx"
+    },
+    "org.nixos.specialisation.v1": {
+        "b": {
+            "org.nixos.bootspec.v1": {
+                "init": "/nix/store/yyy-nixos-system-yyy/init",
+                "kernel": "/nix/store/yyy-linux/bzImage",
+                "kernelParams": [],
+                "label": "NixOS 21.11.20210810.dirty (Linux 6.1.0)",
+                "system": "x86_64-linux",
+                "toplevel": "/nix/store/yyy-nixos-system-yyy"
+            }
+        },
+        "a": {
+            "org.nixos.bootspec.v1": {
+                "init": "/nix/store/zzz-nixos-system-zzz/init",
+                "initrd": "/nix/store/xxx-initrd-linux/initrd",
+                "kernel": "/nix/store/xxx-linux/bzImage",
+                "kernelParams": ["quiet"],
+                "label": "NixOS 21.11.20210810.dirty (Linux 5.15.30)",
+                "system": "x86_64-linux",
+                "toplevel": "/nix/store/zzz-nixos-system-zzz"
+            }
+        }
+    }
+}"#;
+
+    #[test]
+    fn generation_with_specialisations() {
+        let boot_json: BootJson = serde_json::from_str(JSON).unwrap();
+        let entries = entries(42, &boot_json, &Config::default()).unwrap();
+
+        assert_eq!(
+            entries
+                .entries
+                .iter()
+                .map(|e| e.file_name())
+                .collect::<Vec<_>>(),
+            vec![
+                "nixos-generation-42.conf",
+                "nixos-generation-42-specialisation-a.conf",
+                "nixos-generation-42-specialisation-b.conf",
+            ]
+        );
+
+        assert_eq!(
+            entries.entries[0].render(),
+            "title NixOS 21.11.20210810.dirty (Linux 5.15.30)
+version Generation 42
+sort-key nixos
+linux /EFI/nixos/xxx-linux-bzImage.efi
+initrd /EFI/nixos/xxx-initrd-linux-initrd.efi
+options init=/nix/store/xxx-nixos-system-xxx/init loglevel=4 net.ifnames=0
+"
+        );
+        assert_eq!(
+            entries.entries[2].render(),
+            "title NixOS 21.11.20210810.dirty (Linux 6.1.0) (b)
+version Generation 42
+sort-key nixos
+linux /EFI/nixos/yyy-linux-bzImage.efi
+options init=/nix/store/yyy-nixos-system-yyy/init
+"
+        );
+
+        assert_eq!(
+            entries.files,
+            vec![
+                BootFile {
+                    source: PathBuf::from("/nix/store/xxx-linux/bzImage"),
+                    destination: PathBuf::from("/EFI/nixos/xxx-linux-bzImage.efi"),
+                },
+                BootFile {
+                    source: PathBuf::from("/nix/store/xxx-initrd-linux/initrd"),
+                    destination: PathBuf::from("/EFI/nixos/xxx-initrd-linux-initrd.efi"),
+                },
+                BootFile {
+                    source: PathBuf::from("/nix/store/yyy-linux/bzImage"),
+                    destination: PathBuf::from("/EFI/nixos/yyy-linux-bzImage.efi"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_config() {
+        let boot_json: BootJson = serde_json::from_str(JSON).unwrap();
+        let config = Config {
+            esp_dir: PathBuf::from("/EFI/custom"),
+            entry_prefix: String::from("custom"),
+            sort_key: String::from("custom-sort"),
+            ..Default::default()
+        };
+        let entries = entries(7, &boot_json, &config).unwrap();
+
+        assert_eq!(entries.entries[0].id, "custom-generation-7");
+        assert_eq!(entries.entries[0].sort_key, "custom-sort");
+        assert_eq!(
+            entries.entries[0].linux,
+            PathBuf::from("/EFI/custom/xxx-linux-bzImage.efi")
+        );
+    }
+
+    #[test]
+    fn unsafe_specialisation_names() {
+        for name in ["../../../x", "two words", "x.conf", ""] {
+            let json = JSON.replace(r#""b": {"#, &format!(r#"{:?}: {{"#, name));
+            let boot_json: BootJson = serde_json::from_str(&json).unwrap();
+            assert!(matches!(
+                entries(1, &boot_json, &Config::default()),
+                Err(BootspecError::InvalidSpecialisationName(invalid)) if invalid == name
+            ));
+        }
+    }
+
+    #[test]
+    fn initrd_with_secrets() {
+        let mut boot_json: BootJson = serde_json::from_str(JSON).unwrap();
+        let crate::generation::Generation::V1(generation) = &mut boot_json.generation;
+        let tool = PathBuf::from("/nix/store/xxx-nixos-system-xxx/append-initrd-secrets");
+        generation.bootspec.initrd_secrets = Some(tool.clone());
+
+        assert!(matches!(
+            entries(1, &boot_json, &Config::default()),
+            Err(BootspecError::MissingInitrdWithSecrets(missing)) if missing == tool
+        ));
+
+        let mut config = Config::default();
+        config
+            .initrds_with_secrets
+            .insert(tool, PathBuf::from("/boot/initrd-secrets-1234"));
+        let entries = entries(1, &boot_json, &config).unwrap();
+        let destination =
+            PathBuf::from("/EFI/nixos/xxx-nixos-system-xxx-append-initrd-secrets-initrd.efi");
+        assert_eq!(entries.entries[0].initrd, Some(destination.clone()));
+        // The specialisation without the tool still boots the bare initrd.
+        assert_eq!(
+            entries.entries[1].initrd,
+            Some(PathBuf::from("/EFI/nixos/xxx-initrd-linux-initrd.efi"))
+        );
+        assert!(entries.files.contains(&BootFile {
+            source: PathBuf::from("/boot/initrd-secrets-1234"),
+            destination,
+        }));
+    }
+}
@@ -0,0 +1,579 @@
+//! Assemble Unified Kernel Images (UKIs) from a bootspec and an EFI stub (e.g. systemd-stub).
+//!
+//! A UKI is the stub with the kernel, initrd, command line and metadata appended as additional
+//! PE/COFF sections.
+//!
+//! See: <https://uapi-group.org/specifications/specs/unified_kernel_image/>
+use std::fs;
+use std::path::Path;
+
+use crate::error::{BootspecError, UkiError};
+use crate::kernel;
+use crate::v1::BootSpecV1;
+use crate::Result;
+
+const SECTION_HEADER_SIZE: usize = 40;
+const SECTION_NAME_SIZE: usize = 8;
+const SECURITY_DIRECTORY: usize = 4;
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x0000_0040;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+
+/// A PE/COFF section to add to the stub.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    /// The section name, e.g. `.linux`. At most 8 bytes.
+    pub name: String,
+    /// The section contents.
+    pub data: Vec<u8>,
+}
+
+impl Section {
+    fn new(name: &str, data: Vec<u8>) -> Self {
+        Self {
+            name: name.to_string(),
+            data,
+        }
+    }
+}
+
+/// Assemble a UKI for `bootspec` from the EFI stub at `stub`.
+///
+/// The image contains `.osrel` (see [`os_release`]), `.cmdline` (see
+/// [`BootSpecV1::kernel_command_line`]), `.uname` (if [`kernel::inspect_bytes`] finds the kernel
+/// release in the image), `.initrd` (if the bootspec has one) and `.linux`.
+///
+/// `initrd_with_secrets` is the initrd returned by [`BootSpecV1::append_initrd_secrets`], which
+/// is used instead of [`BootSpecV1::initrd`] and must be given if the bootspec has an
+/// `initrdSecrets` tool.
+pub fn assemble(
+    bootspec: &BootSpecV1,
+    initrd_with_secrets: Option<&Path>,
+    stub: &Path,
+) -> Result<Vec<u8>> {
+    let stub = read(stub)?;
+    let sections = sections(bootspec, initrd_with_secrets)?;
+
+    Ok(add_sections(&stub, &sections)?)
+}
+
+/// The sections [`assemble`] adds to the stub, in order.
+pub fn sections(bootspec: &BootSpecV1, initrd_with_secrets: Option<&Path>) -> Result<Vec<Section>> {
+    let mut sections = vec![
+        Section::new(".osrel", os_release(&bootspec.label).into_bytes()),
+        Section::new(".cmdline", bootspec.kernel_command_line().into_bytes()),
+    ];
+    let linux = read(&bootspec.kernel)?;
+    if let Some(uname) = kernel::inspect_bytes(&linux)
+        .ok()
+        .and_then(|image| image.version)
+    {
+        sections.push(Section::new(".uname", uname.into_bytes()));
+    }
+    let initrd = match (&bootspec.initrd_secrets, initrd_with_secrets) {
+        (_, Some(initrd)) => Some(initrd),
+        (Some(tool), None) => return Err(BootspecError::MissingInitrdWithSecrets(tool.clone())),
+        (None, None) => bootspec.initrd.as_deref(),
+    };
+    if let Some(initrd) = initrd {
+        sections.push(Section::new(".initrd", read(initrd)?));
+    }
+    sections.push(Section::new(".linux", linux));
+
+    Ok(sections)
+}
+
+/// The `os-release` contents for a system with the given label.
+pub fn os_release(label: &str) -> String {
+    let mut pretty_name = String::with_capacity(label.len());
+    for c in label.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            pretty_name.push('\\');
+        }
+        pretty_name.push(c);
+    }
+
+    format!("ID=nixos\nPRETTY_NAME=\"{}\"\n", pretty_name)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| BootspecError::ReadPath {
+        path: path.to_path_buf(),
+        err: e,
+    })
+}
+
+/// The parts of a PE/COFF image's headers needed to append sections.
+struct Headers {
+    coff: usize,
+    optional: usize,
+    section_table: usize,
+    sections: Vec<SectionHeader>,
+    section_alignment: u64,
+    file_alignment: u64,
+    size_of_headers: u64,
+    security_directory: Option<usize>,
+}
+
+struct SectionHeader {
+    name: [u8; SECTION_NAME_SIZE],
+    virtual_size: u32,
+    virtual_address: u32,
+    size_of_raw_data: u32,
+    pointer_to_raw_data: u32,
+}
+
+impl Headers {
+    fn parse(image: &[u8]) -> Result<Self, UkiError> {
+        if image.get(0..2) != Some(b"MZ") {
+            return Err(UkiError::InvalidStub("missing DOS signature"));
+        }
+        let pe = read_u32(image, 0x3c)? as usize;
+        if image.get(pe..pe + 4) != Some(b"PE\0\0") {
+            return Err(UkiError::InvalidStub("missing PE signature"));
+        }
+
+        let coff = pe + 4;
+        let number_of_sections = read_u16(image, coff + 2)? as usize;
+        let size_of_optional_header = read_u16(image, coff + 16)? as usize;
+
+        let optional = coff + 20;
+        let data_directories = match read_u16(image, optional)? {
+            0x10b => optional + 96,
+            0x20b => optional + 112,
+            _ => return Err(UkiError::InvalidStub("unknown optional header magic")),
+        };
+        let number_of_data_directories = read_u32(image, data_directories - 4)? as usize;
+        let security_directory = (number_of_data_directories > SECURITY_DIRECTORY)
+            .then_some(data_directories + SECURITY_DIRECTORY * 8);
+        let section_table = optional + size_of_optional_header;
+        if security_directory.unwrap_or(data_directories) + 8 > section_table {
+            return Err(UkiError::InvalidStub("truncated optional header"));
+        }
+
+        let section_alignment = read_u32(image, optional + 32)? as u64;
+        let file_alignment = read_u32(image, optional + 36)? as u64;
+        if section_alignment == 0 || file_alignment == 0 {
+            return Err(UkiError::InvalidStub("zero alignment"));
+        }
+        let size_of_headers = read_u32(image, optional + 60)? as u64;
+        if size_of_headers > image.len() as u64 {
+            return Err(UkiError::InvalidStub(
+                "headers extend past the end of the file",
+            ));
+        }
+        if (section_table + number_of_sections * SECTION_HEADER_SIZE) as u64 > size_of_headers {
+            return Err(UkiError::InvalidStub(
+                "section table extends past the headers",
+            ));
+        }
+
+        let sections = (0..number_of_sections)
+            .map(|i| {
+                let header = section_table + i * SECTION_HEADER_SIZE;
+                let name = image
+                    .get(header..header + SECTION_NAME_SIZE)
+                    .ok_or(UkiError::InvalidStub("truncated section table"))?;
+                Ok(SectionHeader {
+                    name: name.try_into().expect("slice has the right length"),
+                    virtual_size: read_u32(image, header + 8)?,
+                    virtual_address: read_u32(image, header + 12)?,
+                    size_of_raw_data: read_u32(image, header + 16)?,
+                    pointer_to_raw_data: read_u32(image, header + 20)?,
+                })
+            })
+            .collect::<Result<Vec<_>, UkiError>>()?;
+        if sections
+            .iter()
+            .any(|s| s.pointer_to_raw_data as u64 + s.size_of_raw_data as u64 > image.len() as u64)
+        {
+            return Err(UkiError::InvalidStub(
+                "section data extends past the end of the file",
+            ));
+        }
+
+        Ok(Self {
+            coff,
+            optional,
+            section_table,
+            sections,
+            section_alignment,
+            file_alignment,
+            size_of_headers,
+            security_directory,
+        })
+    }
+}
+
+/// Append `sections` to the PE/COFF image `stub`.
+///
+/// Any Authenticode signature on the stub is dropped, as it would no longer be valid.
+pub fn add_sections(stub: &[u8], sections: &[Section]) -> Result<Vec<u8>, UkiError> {
+    let headers = Headers::parse(stub)?;
+
+    for section in sections {
+        if section.name.len() > SECTION_NAME_SIZE {
+            return Err(UkiError::SectionNameTooLong(section.name.clone()));
+        }
+        if headers
+            .sections
+            .iter()
+            .any(|existing| existing.name == section_name(&section.name))
+        {
+            return Err(UkiError::DuplicateSection(section.name.clone()));
+        }
+    }
+
+    let first_data = headers
+        .sections
+        .iter()
+        .filter(|s| s.size_of_raw_data > 0)
+        .map(|s| s.pointer_to_raw_data as u64)
+        .min()
+        .unwrap_or(headers.size_of_headers)
+        .min(headers.size_of_headers);
+    let section_table_end =
+        headers.section_table + (headers.sections.len() + sections.len()) * SECTION_HEADER_SIZE;
+    if section_table_end as u64 > first_data {
+        return Err(UkiError::NoRoomForSections(sections.len()));
+    }
+
+    let mut image = stub.to_vec();
+
+    // The signature lives after the last section and would be invalidated anyway, so drop it
+    // rather than leaving it in the middle of the image.
+    if let Some(security_directory) = headers.security_directory {
+        if read_u32(&image, security_directory)? != 0 {
+            let end_of_sections = headers
+                .sections
+                .iter()
+                .map(|s| s.pointer_to_raw_data as usize + s.size_of_raw_data as usize)
+                .max()
+                .unwrap_or(0)
+                .max(headers.size_of_headers as usize);
+            image.truncate(end_of_sections);
+            write_u32(&mut image, security_directory, 0);
+            write_u32(&mut image, security_directory + 4, 0);
+        }
+    }
+
+    let mut virtual_address = headers
+        .sections
+        .iter()
+        .map(|s| s.virtual_address as u64 + s.virtual_size.max(s.size_of_raw_data) as u64)
+        .max()
+        .unwrap_or(headers.size_of_headers);
+    virtual_address = align(virtual_address, headers.section_alignment);
+    let mut initialized_data = read_u32(&image, headers.optional + 8)? as u64;
+
+    for (i, section) in sections.iter().enumerate() {
+        let pointer_to_raw_data = align(image.len() as u64, headers.file_alignment);
+        let size_of_raw_data = align(section.data.len() as u64, headers.file_alignment);
+        image.resize(pointer_to_raw_data as usize, 0);
+        image.extend_from_slice(&section.data);
+        image.resize((pointer_to_raw_data + size_of_raw_data) as usize, 0);
+
+        let header = headers.section_table + (headers.sections.len() + i) * SECTION_HEADER_SIZE;
+        image[header..header + SECTION_NAME_SIZE].copy_from_slice(&section_name(&section.name));
+        write_u32(&mut image, header + 8, to_u32(section.data.len() as u64)?);
+        write_u32(&mut image, header + 12, to_u32(virtual_address)?);
+        write_u32(&mut image, header + 16, to_u32(size_of_raw_data)?);
+        write_u32(&mut image, header + 20, to_u32(pointer_to_raw_data)?);
+        image[header + 24..header + 36].fill(0);
+        write_u32(
+            &mut image,
+            header + 36,
+            IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+        );
+
+        virtual_address = align(
+            virtual_address + section.data.len() as u64,
+            headers.section_alignment,
+        );
+        initialized_data += size_of_raw_data;
+    }
+
+    let number_of_sections = u16::try_from(headers.sections.len() + sections.len())
+        .map_err(|_| UkiError::NoRoomForSections(sections.len()))?;
+    image[headers.coff + 2..headers.coff + 4].copy_from_slice(&number_of_sections.to_le_bytes());
+    write_u32(&mut image, headers.optional + 8, to_u32(initialized_data)?);
+    write_u32(&mut image, headers.optional + 56, to_u32(virtual_address)?);
+    // The checksum is not verified by EFI firmware, and a stale one is worse than none.
+    write_u32(&mut image, headers.optional + 64, 0);
+
+    if image.len() as u64 > u32::MAX as u64 {
+        return Err(UkiError::TooLarge);
+    }
+
+    Ok(image)
+}
+
+fn section_name(name: &str) -> [u8; SECTION_NAME_SIZE] {
+    let mut padded = [0; SECTION_NAME_SIZE];
+    padded[..name.len()].copy_from_slice(name.as_bytes());
+    padded
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+fn to_u32(value: u64) -> Result<u32, UkiError> {
+    u32::try_from(value).map_err(|_| UkiError::TooLarge)
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, UkiError> {
+    image
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(UkiError::InvalidStub("truncated headers"))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, UkiError> {
+    image
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(UkiError::InvalidStub("truncated headers"))
+}
+
+fn write_u32(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use super::{add_sections, assemble, os_release, read_u16, read_u32, Headers, Section};
+    use crate::error::{BootspecError, UkiError};
+    use crate::v1::BootSpecV1;
+    use crate::SystemConfigurationRoot;
+
+    /// A minimal PE32+ image with a single `.text` section and room for 16 more section headers.
+    fn stub() -> Vec<u8> {
+        let mut image = vec![0u8; 0x600];
+        image[0..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+
+        let coff = 0x44;
+        image[coff..coff + 2].copy_from_slice(&0x8664u16.to_le_bytes());
+        image[coff + 2..coff + 4].copy_from_slice(&1u16.to_le_bytes());
+        image[coff + 16..coff + 18].copy_from_slice(&240u16.to_le_bytes());
+
+        let optional = coff + 20;
+        image[optional..optional + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[optional + 32..optional + 36].copy_from_slice(&0x1000u32.to_le_bytes());
+        image[optional + 36..optional + 40].copy_from_slice(&0x200u32.to_le_bytes());
+        image[optional + 56..optional + 60].copy_from_slice(&0x2000u32.to_le_bytes());
+        image[optional + 60..optional + 64].copy_from_slice(&0x400u32.to_le_bytes());
+        image[optional + 108..optional + 112].copy_from_slice(&16u32.to_le_bytes());
+
+        let text = optional + 240;
+        image[text..text + 5].copy_from_slice(b".text");
+        image[text + 8..text + 12].copy_from_slice(&0x10u32.to_le_bytes());
+        image[text + 12..text + 16].copy_from_slice(&0x1000u32.to_le_bytes());
+        image[text + 16..text + 20].copy_from_slice(&0x200u32.to_le_bytes());
+        image[text + 20..text + 24].copy_from_slice(&0x400u32.to_le_bytes());
+        image[0x400..0x410].fill(0xcc);
+
+        image
+    }
+
+    fn section_data<'a>(image: &'a [u8], name: &str) -> Option<&'a [u8]> {
+        let headers = Headers::parse(image).unwrap();
+        let section = headers
+            .sections
+            .iter()
+            .find(|s| s.name == super::section_name(name))?;
+        let start = section.pointer_to_raw_data as usize;
+        Some(&image[start..start + section.virtual_size as usize])
+    }
+
+    #[test]
+    fn adds_sections() {
+        let stub = stub();
+        let image = add_sections(
+            &stub,
+            &[
+                Section::new(".cmdline", b"init=/init quiet".to_vec()),
+                Section::new(".linux", vec![0xaa; 0x1234]),
+            ],
+        )
+        .unwrap();
+
+        let headers = Headers::parse(&image).unwrap();
+        assert_eq!(headers.sections.len(), 3);
+        assert_eq!(read_u16(&image, headers.coff + 2).unwrap(), 3);
+
+        let cmdline = &headers.sections[1];
+        assert_eq!(cmdline.virtual_address, 0x2000);
+        assert_eq!(cmdline.pointer_to_raw_data, 0x600);
+        assert_eq!(cmdline.size_of_raw_data, 0x200);
+
+        let linux = &headers.sections[2];
+        assert_eq!(linux.virtual_address, 0x3000);
+        assert_eq!(linux.pointer_to_raw_data, 0x800);
+        assert_eq!(linux.size_of_raw_data, 0x1400);
+        assert_eq!(image.len(), 0x1c00);
+
+        // SizeOfImage
+        assert_eq!(read_u32(&image, headers.optional + 56).unwrap(), 0x5000);
+        assert_eq!(
+            section_data(&image, ".cmdline").unwrap(),
+            b"init=/init quiet"
+        );
+        assert_eq!(section_data(&image, ".linux").unwrap(), &[0xaa; 0x1234][..]);
+        assert_eq!(section_data(&image, ".text").unwrap(), &[0xcc; 0x10][..]);
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        let stub = stub();
+
+        assert!(matches!(
+            add_sections(b"not a PE image", &[]),
+            Err(UkiError::InvalidStub(_))
+        ));
+        assert!(matches!(
+            add_sections(&stub, &[Section::new(".text", vec![])]),
+            Err(UkiError::DuplicateSection(name)) if name == ".text"
+        ));
+        assert!(matches!(
+            add_sections(&stub, &[Section::new(".toolongname", vec![])]),
+            Err(UkiError::SectionNameTooLong(_))
+        ));
+
+        let many = (0..17)
+            .map(|i| Section::new(&format!(".s{}", i), vec![]))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            add_sections(&stub, &many),
+            Err(UkiError::NoRoomForSections(17))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_stubs() {
+        let sections = [Section::new(".cmdline", b"quiet".to_vec())];
+
+        // Every prefix of the stub ends inside its headers or its `.text` section.
+        let stub = stub();
+        for len in 0..stub.len() {
+            assert!(
+                matches!(
+                    add_sections(&stub[..len], &sections),
+                    Err(UkiError::InvalidStub(_))
+                ),
+                "truncated to {:#x} bytes",
+                len
+            );
+        }
+
+        let mut past_end = stub.clone();
+        past_end[0x3c..0x40].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+        assert!(matches!(
+            add_sections(&past_end, &sections),
+            Err(UkiError::InvalidStub(_))
+        ));
+
+        let mut past_end = stub.clone();
+        // SizeOfHeaders
+        past_end[0x58 + 60..0x58 + 64].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert!(matches!(
+            add_sections(&past_end, &sections),
+            Err(UkiError::InvalidStub(_))
+        ));
+
+        let mut past_end = stub;
+        // SizeOfOptionalHeader
+        past_end[0x44 + 16..0x44 + 18].copy_from_slice(&0xfff0u16.to_le_bytes());
+        assert!(matches!(
+            add_sections(&past_end, &sections),
+            Err(UkiError::InvalidStub(_))
+        ));
+    }
+
+    #[test]
+    fn assembles_from_bootspec() {
+        let dir = TempDir::new().unwrap();
+        let stub_path = dir.path().join("linuxx64.efi.stub");
+        fs::write(&stub_path, stub()).unwrap();
+        // An arm64 `Image` with its version banner.
+        let mut kernel = vec![0; 0x40];
+        kernel[0x38..0x3c].copy_from_slice(b"ARM\x64");
+        kernel.extend_from_slice(b"Linux version 6.1.0-rc1 (nixbld@localhost) #1-NixOS SMP\0");
+        fs::write(dir.path().join("bzImage"), &kernel).unwrap();
+        fs::write(dir.path().join("initrd"), b"initrd").unwrap();
+
+        let bootspec = BootSpecV1 {
+            label: String::from("NixOS 23.05 (Linux 6.1.0)"),
+            kernel: dir.path().join("bzImage"),
+            kernel_params: vec!["loglevel=4"].into(),
+            init: PathBuf::from("/nix/store/xxx-nixos-system-xxx/init"),
+            initrd: Some(dir.path().join("initrd")),
+            initrd_secrets: None,
+            system: String::from("x86_64-linux"),
+            toplevel: SystemConfigurationRoot(PathBuf::from("/nix/store/xxx-nixos-system-xxx")),
+            unknown_fields: Default::default(),
+        };
+
+        let image = assemble(&bootspec, None, &stub_path).unwrap();
+
+        assert_eq!(
+            section_data(&image, ".osrel").unwrap(),
+            b"ID=nixos\nPRETTY_NAME=\"NixOS 23.05 (Linux 6.1.0)\"\n"
+        );
+        assert_eq!(
+            section_data(&image, ".cmdline").unwrap(),
+            b"init=/nix/store/xxx-nixos-system-xxx/init loglevel=4"
+        );
+        assert_eq!(section_data(&image, ".uname").unwrap(), b"6.1.0-rc1");
+        assert_eq!(section_data(&image, ".initrd").unwrap(), b"initrd");
+        assert_eq!(section_data(&image, ".linux").unwrap(), &kernel[..]);
+
+        // With an initrdSecrets tool, the initrd it appended secrets to is required.
+        fs::write(dir.path().join("initrd-secrets"), b"initrd\nsecrets").unwrap();
+        let with_secrets = BootSpecV1 {
+            initrd_secrets: Some(PathBuf::from(
+                "/nix/store/xxx-nixos-system-xxx/append-initrd-secrets",
+            )),
+            ..bootspec.clone()
+        };
+        assert!(matches!(
+            assemble(&with_secrets, None, &stub_path),
+            Err(BootspecError::MissingInitrdWithSecrets(_))
+        ));
+        let image = assemble(
+            &with_secrets,
+            Some(&dir.path().join("initrd-secrets")),
+            &stub_path,
+        )
+        .unwrap();
+        assert_eq!(section_data(&image, ".initrd").unwrap(), b"initrd\nsecrets");
+
+        // Without a version in the image, the label is not trusted for `.uname`.
+        fs::write(dir.path().join("bzImage"), b"kernel").unwrap();
+        let image = assemble(&bootspec, None, &stub_path).unwrap();
+        assert_eq!(section_data(&image, ".uname"), None);
+
+        let missing = BootSpecV1 {
+            kernel: dir.path().join("missing"),
+            ..bootspec
+        };
+        assert!(matches!(
+            assemble(&missing, None, &stub_path),
+            Err(BootspecError::ReadPath { path, .. }) if path == dir.path().join("missing")
+        ));
+    }
+
+    #[test]
+    fn metadata_from_label() {
+        assert_eq!(
+            os_release(r#"My "$system""#),
+            "ID=nixos\nPRETTY_NAME=\"My \\\"\\$system\\\"\"\n"
+        );
+    }
+}
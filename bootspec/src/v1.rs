@@ -7,6 +7,10 @@ use serde::{Deserialize, Serialize};
 
 use crate::deser;
 use crate::error::{BootspecError, SynthesizeError};
+use crate::generation::UnknownFields;
+use crate::kernel_params::{KernelParam, KernelParams};
+use crate::synthesis::{kernel_image_version, SynthesisWarning, SynthesisWarningKind, Warnings};
+use crate::validation::escape;
 use crate::{Extensions, Result, SpecialisationName, SystemConfigurationRoot};
 
 /// The V1 bootspec schema version.
@@ -22,19 +26,92 @@ pub const SCHEMA_VERSION: u64 = 1;
 /// If you attempt to deserialize using this struct, you will not get any information about
 /// user-provided extensions. For that, you must deserialize with [`crate::BootJson`].
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct GenerationV1 {
     #[serde(rename = "org.nixos.bootspec.v1")]
     pub bootspec: BootSpecV1,
-    #[serde(rename = "org.nixos.specialisation.v1", default = "HashMap::new")]
+    #[serde(
+        rename = "org.nixos.specialisation.v1",
+        default = "HashMap::new",
+        serialize_with = "deser::serialize_sorted"
+    )]
     pub specialisations: SpecialisationsV1,
 }
 
 impl GenerationV1 {
+    /// Apply `mode` to the fields of the `org.nixos.bootspec.v1` objects in `raw`, the document
+    /// this generation (and its specialisations) was deserialized from. `pointer` is the JSON
+    /// pointer of `raw`.
+    pub(crate) fn unknown_fields(
+        &mut self,
+        raw: &serde_json::Map<String, serde_json::Value>,
+        pointer: &str,
+        mode: UnknownFields,
+    ) -> Result<()> {
+        if mode == UnknownFields::Drop {
+            return Ok(());
+        }
+
+        if let Some(serde_json::Value::Object(bootspec)) = raw.get("org.nixos.bootspec.v1") {
+            let mut unknown = bootspec
+                .iter()
+                .filter(|(key, _)| !BootSpecV1::FIELDS.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect::<serde_json::Map<_, _>>();
+
+            if mode == UnknownFields::Deny {
+                if let Some(key) = unknown.keys().next() {
+                    return Err(BootspecError::UnknownField(format!(
+                        "{}/{}/{}",
+                        pointer,
+                        escape("org.nixos.bootspec.v1"),
+                        escape(key)
+                    )));
+                }
+            }
+            self.bootspec.unknown_fields.append(&mut unknown);
+        }
+
+        if let Some(serde_json::Value::Object(specialisations)) =
+            raw.get("org.nixos.specialisation.v1")
+        {
+            for (name, specialisation) in &mut self.specialisations {
+                if let Some(serde_json::Value::Object(raw)) = specialisations.get(&name.0) {
+                    let pointer = format!(
+                        "{}/{}/{}",
+                        pointer,
+                        escape("org.nixos.specialisation.v1"),
+                        escape(&name.0)
+                    );
+                    specialisation
+                        .generation
+                        .unknown_fields(raw, &pointer, mode)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start building a [`GenerationV1`].
+    pub fn builder() -> GenerationV1Builder {
+        GenerationV1Builder::default()
+    }
+
     /// Synthesize a [`GenerationV1`] struct from the path to a NixOS generation.
     ///
     /// This is useful when used on generations that do not have a bootspec attached to it.
     pub fn synthesize(generation_path: &Path) -> Result<Self> {
-        let bootspec = BootSpecV1::synthesize(generation_path)?;
+        Self::synthesize_reporting(generation_path, &mut Vec::new())
+    }
+
+    /// Like [`GenerationV1::synthesize`], pushing the guesses made for this generation and its
+    /// specialisations onto `warnings`.
+    pub(crate) fn synthesize_reporting(
+        generation_path: &Path,
+        warnings: &mut Vec<SynthesisWarning>,
+    ) -> Result<Self> {
+        let bootspec = BootSpecV1::synthesize(generation_path, warnings)?;
 
         let mut specialisations = HashMap::new();
         if let Ok(specialisations_dirs) = fs::read_dir(generation_path.join("specialisation")) {
@@ -49,7 +126,7 @@ impl GenerationV1 {
 
                 specialisations.insert(
                     SpecialisationName(name.to_string()),
-                    SpecialisationV1::synthesize(&toplevel)?,
+                    SpecialisationV1::new(Self::synthesize_reporting(&toplevel, warnings)?),
                 );
             }
         }
@@ -70,12 +147,14 @@ pub type SpecialisationsV1 = HashMap<SpecialisationName, SpecialisationV1>;
 ///
 /// This structure represents a single specialisation contained in the `org.nixos.specialisation.v1` key.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct SpecialisationV1 {
     #[serde(flatten)]
     pub generation: GenerationV1,
     #[serde(
         default = "HashMap::new",
         skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "deser::serialize_sorted",
         deserialize_with = "deser::skip_generation_fields",
         flatten
     )]
@@ -83,6 +162,16 @@ pub struct SpecialisationV1 {
 }
 
 impl SpecialisationV1 {
+    /// A specialisation booting `generation`, without extensions.
+    ///
+    /// Use [`SpecialisationV1::set_extension`] to add extensions.
+    pub fn new(generation: GenerationV1) -> Self {
+        Self {
+            generation,
+            extensions: HashMap::new(),
+        }
+    }
+
     /// Synthesize a [`SpecialisationV1`] struct from the path to a NixOS generation.
     ///
     /// This is useful when used on generations that do not have a bootspec attached to it.
@@ -99,28 +188,134 @@ impl SpecialisationV1 {
 ///
 /// This structure represents the contents of the `org.nixos.bootspec.v1` key.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+// The Rust documentation is not part of the schema.
+#[cfg_attr(
+    feature = "schemars",
+    schemars(rename = "BootspecV1", description = "")
+)]
 #[serde(rename_all = "camelCase")]
+#[non_exhaustive]
 pub struct BootSpecV1 {
     /// Label for the system closure
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(
+            description = "A human-readable label for the system. It should contain the operating system, kernel version,and other user-relevant information to identify the system. This corresponds loosely to `config.system.nixos.label`.",
+            example = &"NixOS 21.11.20210810.dirty (Linux 5.15.30)"
+        )
+    )]
     pub label: String,
     /// Path to kernel (bzImage) -- $toplevel/kernel
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(
+            with = "crate::schema::NixStorePath",
+            description = "Nix store path to the kernel image."
+        )
+    )]
     pub kernel: PathBuf,
     /// list of kernel parameters
-    pub kernel_params: Vec<String>,
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(
+            with = "Vec<KernelParam>",
+            description = "List of kernel parameters",
+            example = crate::schema::KERNEL_PARAMS_EXAMPLE
+        )
+    )]
+    pub kernel_params: KernelParams,
     /// Path to the init script
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(
+            with = "crate::schema::NixStorePath",
+            description = "Nix store path to the stage-2 init, executed by initrd (if present)."
+        )
+    )]
     pub init: PathBuf,
     /// Path to initrd -- $toplevel/initrd
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(
+            with = "Option<crate::schema::NixStorePath>",
+            description = "Nix store path to the initrd."
+        )
+    )]
     pub initrd: Option<PathBuf>,
     /// Path to "append-initrd-secrets" script -- $toplevel/append-initrd-secrets
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(
+            with = "Option<crate::schema::NixStorePath>",
+            description = "Nix store path to a tool that dynamically adds secrets to initrd. Consumers of a bootspec document should copy the file referenced by the `initrd` key to a writable location, ensure that the file is writable, invoke this tool with the path to the initrd as its only argument, and use the initrd as modified by the tool for booting. This may be used to add files from outside the Nix store to the initrd. This tool is expected to run on the system whose boot specification is being set up, and may thus fail if used on a system where the expected stateful files are not in place or whose CPU does not support the instruction set of the system to be booted. If this field is present and the tool fails, no boot configuration should be generated for the system."
+        )
+    )]
     pub initrd_secrets: Option<PathBuf>,
     /// System double, e.g. x86_64-linux, for the system closure
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(
+            description = "Nix system type the bootspec is intended for.",
+            example = &"x86_64-linux",
+            example = &"aarch64-linux"
+        )
+    )]
     pub system: String,
     /// config.system.build.toplevel path
+    #[cfg_attr(
+        feature = "schemars",
+        schemars(
+            with = "crate::schema::NixStorePath",
+            description = "Top-level Nix store path of the system closure."
+        )
+    )]
     pub toplevel: SystemConfigurationRoot,
+    /// Fields of `org.nixos.bootspec.v1` that this crate does not know, e.g. ones written by a
+    /// newer producer. These are written back out when serializing.
+    ///
+    /// This is only filled when parsing with [`UnknownFields::Preserve`].
+    #[serde(
+        flatten,
+        skip_deserializing,
+        skip_serializing_if = "serde_json::Map::is_empty"
+    )]
+    pub unknown_fields: serde_json::Map<String, serde_json::Value>,
 }
 
 impl BootSpecV1 {
-    pub(crate) fn synthesize(generation: &Path) -> Result<Self> {
+    /// The fields of `org.nixos.bootspec.v1` this crate knows.
+    pub const FIELDS: &'static [&'static str] = &[
+        "label",
+        "kernel",
+        "kernelParams",
+        "init",
+        "initrd",
+        "initrdSecrets",
+        "system",
+        "toplevel",
+    ];
+
+    /// Start building a [`BootSpecV1`].
+    pub fn builder() -> BootSpecV1Builder {
+        BootSpecV1Builder::default()
+    }
+
+    /// The kernel command line a bootloader should pass: `init=` followed by the kernel
+    /// parameters.
+    pub fn kernel_command_line(&self) -> String {
+        let mut cmdline = format!("init={}", self.init.display());
+        if !self.kernel_params.is_empty() {
+            cmdline.push(' ');
+            cmdline.push_str(&self.kernel_params.to_string());
+        }
+        cmdline
+    }
+
+    pub(crate) fn synthesize(
+        generation: &Path,
+        warnings: &mut Vec<SynthesisWarning>,
+    ) -> Result<Self> {
         let generation = generation
             .canonicalize()
             .map_err(|e| SynthesizeError::Canonicalize {
@@ -149,33 +344,79 @@ impl BootSpecV1 {
                 err: e,
             })?;
 
-        let kernel_modules_path = generation.join("kernel-modules/lib/modules");
-        let kernel_modules = fs::canonicalize(kernel_modules_path.clone()).map_err(|e| {
-            SynthesizeError::Canonicalize {
-                path: kernel_modules_path,
-                err: e,
+        let mut warnings = Warnings::new(&generation, warnings);
+        let kernel_modules = generation.join("kernel-modules/lib/modules");
+        // Kernels without loadable modules have no module tree at all.
+        let versioned_kernel_modules = if kernel_modules.exists() {
+            let kernel_modules = fs::canonicalize(kernel_modules.clone()).map_err(|e| {
+                SynthesizeError::Canonicalize {
+                    path: kernel_modules.clone(),
+                    err: e,
+                }
+            })?;
+            fs::read_dir(kernel_modules.clone())
+                .map_err(|e| SynthesizeError::ReadPath {
+                    path: kernel_modules,
+                    err: e,
+                })?
+                .map(|res| res.map(|e| e.path()))
+                .collect::<std::io::Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+        let mut kernel_versions = Vec::new();
+        for versioned_kernel_modules in &versioned_kernel_modules {
+            let kernel_version = versioned_kernel_modules
+                .file_name()
+                .ok_or(BootspecError::InvalidFileName(
+                    versioned_kernel_modules.clone(),
+                ))?
+                .to_str()
+                .ok_or(BootspecError::InvalidUtf8(versioned_kernel_modules.clone()))?;
+            kernel_versions.push(kernel_version.to_string());
+        }
+        kernel_versions.sort();
+        let kernel_version = match kernel_versions.as_slice() {
+            [] => kernel_image_version(&kernel)?
+                .ok_or(SynthesizeError::MissingKernelVersionDir(kernel_modules))?,
+            [kernel_version] => kernel_version.clone(),
+            _ => {
+                // Several module trees (e.g. out-of-tree module overlays): only the one built
+                // for the kernel image is right.
+                let image_version = kernel_image_version(&kernel)?;
+                let chosen = image_version.as_deref().and_then(|image_version| {
+                    kernel_versions
+                        .iter()
+                        .find(|candidate| *candidate == image_version)
+                });
+                let Some(kernel_version) = chosen.cloned() else {
+                    return Err(SynthesizeError::AmbiguousKernelVersion {
+                        path: kernel_modules,
+                        image_version,
+                        candidates: kernel_versions,
+                    }
+                    .into());
+                };
+
+                warnings.push(SynthesisWarningKind::MultipleKernelModuleDirs {
+                    chosen: kernel_version.clone(),
+                    candidates: kernel_versions,
+                });
+                kernel_version
             }
-        })?;
-        let versioned_kernel_modules = fs::read_dir(kernel_modules.clone())
-            .map_err(|e| SynthesizeError::ReadPath {
-                path: kernel_modules.clone(),
-                err: e,
-            })?
-            .map(|res| res.map(|e| e.path()))
-            .next()
-            .ok_or(SynthesizeError::MissingKernelVersionDir(kernel_modules))??;
-        let kernel_version = versioned_kernel_modules
-            .file_name()
-            .ok_or(BootspecError::InvalidFileName(
-                versioned_kernel_modules.clone(),
-            ))?
-            .to_str()
-            .ok_or(BootspecError::InvalidUtf8(versioned_kernel_modules.clone()))?;
-
-        let kernel_params: Vec<String> = fs::read_to_string(generation.join("kernel-params"))?
-            .split(' ')
-            .map(str::to_string)
-            .collect();
+        };
+
+        let raw_kernel_params = fs::read_to_string(generation.join("kernel-params"))?;
+        let kernel_params = KernelParams::parse(&raw_kernel_params);
+        if raw_kernel_params.matches('"').count() % 2 != 0 {
+            warnings.push(SynthesisWarningKind::UnterminatedQuote);
+        }
+        let duplicates = kernel_params.duplicates();
+        if !duplicates.is_empty() {
+            warnings.push(SynthesisWarningKind::DuplicateKernelParams(
+                duplicates.into_iter().map(str::to_string).collect(),
+            ));
+        }
 
         let init = generation.join("init");
 
@@ -188,6 +429,7 @@ impl BootSpecV1 {
                 }
             })?)
         } else {
+            warnings.push(SynthesisWarningKind::NoInitrd);
             None
         };
 
@@ -206,16 +448,148 @@ impl BootSpecV1 {
             initrd_secrets,
             system,
             toplevel: SystemConfigurationRoot(generation),
+            unknown_fields: serde_json::Map::new(),
+        })
+    }
+}
+
+/// A builder for [`BootSpecV1`], created by [`BootSpecV1::builder`].
+///
+/// `label`, `kernel`, `init`, `system` and `toplevel` are required. The kernel parameters default
+/// to none.
+#[derive(Debug, Clone, Default)]
+pub struct BootSpecV1Builder {
+    label: Option<String>,
+    kernel: Option<PathBuf>,
+    kernel_params: KernelParams,
+    init: Option<PathBuf>,
+    initrd: Option<PathBuf>,
+    initrd_secrets: Option<PathBuf>,
+    system: Option<String>,
+    toplevel: Option<PathBuf>,
+}
+
+impl BootSpecV1Builder {
+    /// Set the label for the system closure.
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Set the path to the kernel.
+    pub fn kernel(mut self, kernel: impl Into<PathBuf>) -> Self {
+        self.kernel = Some(kernel.into());
+        self
+    }
+
+    /// Replace the kernel parameters.
+    pub fn kernel_params(mut self, kernel_params: impl Into<KernelParams>) -> Self {
+        self.kernel_params = kernel_params.into();
+        self
+    }
+
+    /// Append a kernel parameter.
+    pub fn kernel_param(mut self, kernel_param: impl Into<KernelParam>) -> Self {
+        self.kernel_params.push(kernel_param);
+        self
+    }
+
+    /// Set the path to the init script.
+    pub fn init(mut self, init: impl Into<PathBuf>) -> Self {
+        self.init = Some(init.into());
+        self
+    }
+
+    /// Set the path to the initrd.
+    pub fn initrd(mut self, initrd: impl Into<PathBuf>) -> Self {
+        self.initrd = Some(initrd.into());
+        self
+    }
+
+    /// Set the path to the `append-initrd-secrets` script.
+    pub fn initrd_secrets(mut self, initrd_secrets: impl Into<PathBuf>) -> Self {
+        self.initrd_secrets = Some(initrd_secrets.into());
+        self
+    }
+
+    /// Set the system double, e.g. `x86_64-linux`.
+    pub fn system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    /// Set the top-level path of the system closure.
+    pub fn toplevel(mut self, toplevel: impl Into<PathBuf>) -> Self {
+        self.toplevel = Some(toplevel.into());
+        self
+    }
+
+    /// Build the [`BootSpecV1`], or return [`BootspecError::MissingField`] naming the first
+    /// required field that was not set.
+    pub fn build(self) -> Result<BootSpecV1> {
+        let missing = BootspecError::MissingField;
+
+        Ok(BootSpecV1 {
+            label: self.label.ok_or(missing("label"))?,
+            kernel: self.kernel.ok_or(missing("kernel"))?,
+            kernel_params: self.kernel_params,
+            init: self.init.ok_or(missing("init"))?,
+            initrd: self.initrd,
+            initrd_secrets: self.initrd_secrets,
+            system: self.system.ok_or(missing("system"))?,
+            toplevel: SystemConfigurationRoot(self.toplevel.ok_or(missing("toplevel"))?),
+            unknown_fields: serde_json::Map::new(),
+        })
+    }
+}
+
+/// A builder for [`GenerationV1`], created by [`GenerationV1::builder`].
+///
+/// The bootspec is required.
+#[derive(Debug, Clone, Default)]
+pub struct GenerationV1Builder {
+    bootspec: Option<BootSpecV1>,
+    specialisations: SpecialisationsV1,
+}
+
+impl GenerationV1Builder {
+    /// Set the generation's bootspec.
+    pub fn bootspec(mut self, bootspec: BootSpecV1) -> Self {
+        self.bootspec = Some(bootspec);
+        self
+    }
+
+    /// Add a specialisation, replacing any previous one with the same name.
+    pub fn specialisation(
+        mut self,
+        name: impl Into<String>,
+        specialisation: SpecialisationV1,
+    ) -> Self {
+        self.specialisations
+            .insert(SpecialisationName(name.into()), specialisation);
+        self
+    }
+
+    /// Build the [`GenerationV1`], or return [`BootspecError::MissingField`] if the bootspec was
+    /// not set.
+    pub fn build(self) -> Result<GenerationV1> {
+        Ok(GenerationV1 {
+            bootspec: self
+                .bootspec
+                .ok_or(BootspecError::MissingField("org.nixos.bootspec.v1"))?,
+            specialisations: self.specialisations,
         })
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::fs;
     use std::path::{Path, PathBuf};
 
-    use super::{BootSpecV1, SystemConfigurationRoot};
+    use super::{BootSpecV1, GenerationV1, SpecialisationV1, SystemConfigurationRoot};
+    use crate::error::BootspecError;
+    use crate::kernel_params::{KernelParam, KernelParams};
     use crate::JSON_FILENAME;
     use tempfile::TempDir;
 
@@ -247,7 +621,7 @@ mod tests {
             .expect("Failed to write to test generation");
     }
 
-    fn scaffold(
+    pub(crate) fn scaffold(
         system: &str,
         system_version: &str,
         kernel_version: &str,
@@ -311,7 +685,7 @@ mod tests {
             None,
             false,
         );
-        let spec = BootSpecV1::synthesize(&generation).unwrap();
+        let spec = BootSpecV1::synthesize(&generation, &mut Vec::new()).unwrap();
 
         assert_eq!(
             spec,
@@ -319,11 +693,12 @@ mod tests {
                 system,
                 label: "NixOS test-version-1 (Linux 1.1.1-test1)".into(),
                 kernel: generation.join("kernel"),
-                kernel_params,
+                kernel_params: kernel_params.into(),
                 init: generation.join("init"),
                 initrd: Some(generation.join("initrd")),
                 initrd_secrets: Some(generation.join("append-initrd-secrets")),
                 toplevel: SystemConfigurationRoot(generation),
+                unknown_fields: Default::default(),
             }
         );
     }
@@ -352,7 +727,7 @@ mod tests {
             false,
         );
 
-        BootSpecV1::synthesize(&generation).unwrap();
+        BootSpecV1::synthesize(&generation, &mut Vec::new()).unwrap();
     }
 
     #[test]
@@ -380,7 +755,7 @@ mod tests {
 
         fs::write(generation.join(JSON_FILENAME), "").expect("Failed to write to test generation");
 
-        let spec = BootSpecV1::synthesize(&generation).unwrap();
+        let spec = BootSpecV1::synthesize(&generation, &mut Vec::new()).unwrap();
 
         assert_eq!(
             spec,
@@ -388,11 +763,12 @@ mod tests {
                 system,
                 label: "NixOS test-version-3 (Linux 1.1.1-test3)".into(),
                 kernel: generation.join("kernel"),
-                kernel_params,
+                kernel_params: kernel_params.into(),
                 init: generation.join("init"),
                 initrd: Some(generation.join("initrd")),
                 initrd_secrets: Some(generation.join("append-initrd-secrets")),
-                toplevel: SystemConfigurationRoot(generation)
+                toplevel: SystemConfigurationRoot(generation),
+                unknown_fields: Default::default(),
             }
         );
     }
@@ -424,6 +800,81 @@ mod tests {
         fs::write(generation.join("bootspec").join(JSON_FILENAME), "")
             .expect("Failed to write to test generation");
 
-        BootSpecV1::synthesize(&generation).unwrap();
+        BootSpecV1::synthesize(&generation, &mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn kernel_params_with_irregular_whitespace() {
+        let generation = scaffold(
+            "x86_64-linux",
+            "test-version-5",
+            "1.1.1-test5",
+            &[],
+            None,
+            false,
+        );
+        fs::write(
+            generation.join("kernel-params"),
+            "loglevel=4  quiet foo=\"bar baz\"\n",
+        )
+        .expect("Failed to write to test generation");
+
+        let spec = BootSpecV1::synthesize(&generation, &mut Vec::new()).unwrap();
+
+        assert_eq!(
+            spec.kernel_params,
+            KernelParams::from(vec![
+                KernelParam::pair("loglevel", "4"),
+                KernelParam::flag("quiet"),
+                KernelParam::pair("foo", "bar baz"),
+            ])
+        );
+    }
+
+    #[test]
+    fn builders() {
+        let bootspec = BootSpecV1::builder()
+            .label("NixOS 21.11 (Linux 5.15.30)")
+            .kernel("/nix/store/xxx-linux/bzImage")
+            .kernel_params(vec!["loglevel=4"])
+            .kernel_param(KernelParam::flag("quiet"))
+            .init("/nix/store/xxx-nixos-system-xxx/init")
+            .initrd("/nix/store/xxx-initrd-linux/initrd")
+            .system("x86_64-linux")
+            .toplevel("/nix/store/xxx-nixos-system-xxx")
+            .build()
+            .unwrap();
+        assert_eq!(bootspec.kernel_params.to_string(), "loglevel=4 quiet");
+        assert_eq!(bootspec.initrd_secrets, None);
+
+        let generation = GenerationV1::builder()
+            .bootspec(bootspec.clone())
+            .specialisation(
+                "dark",
+                SpecialisationV1::new(
+                    GenerationV1::builder()
+                        .bootspec(bootspec.clone())
+                        .build()
+                        .unwrap(),
+                ),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(generation.bootspec, bootspec);
+        assert_eq!(generation.specialisations.len(), 1);
+
+        let err = BootSpecV1::builder()
+            .label("NixOS")
+            .kernel("/nix/store/xxx-linux/bzImage")
+            .init("/nix/store/xxx-nixos-system-xxx/init")
+            .toplevel("/nix/store/xxx-nixos-system-xxx")
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, BootspecError::MissingField("system")));
+
+        assert!(matches!(
+            GenerationV1::builder().build(),
+            Err(BootspecError::MissingField("org.nixos.bootspec.v1"))
+        ));
     }
 }
@@ -0,0 +1,950 @@
+//! Semantic validation of bootspec documents, beyond what deserialization checks.
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(feature = "initrd")]
+use crate::error::BootspecError;
+use crate::extension;
+use crate::generation::Generation;
+#[cfg(feature = "initrd")]
+use crate::initrd::Initrd;
+use crate::kernel;
+use crate::kernel_params::KernelParam;
+use crate::v1::{self, BootSpecV1, GenerationV1};
+use crate::versions::SUPPORTED_VERSIONS;
+use crate::{BootJson, Extensions};
+
+/// The Nix store directory used by default.
+pub const DEFAULT_STORE_DIR: &str = "/nix/store";
+
+/// Settings that control which checks [`BootJson::validate`] performs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationOptions {
+    /// The Nix store directory all paths are expected to be in.
+    pub store_dir: PathBuf,
+    /// Whether to check that the referenced paths exist, have the right file type, and resolve
+    /// inside the Nix store. Off by default, because documents are often validated on a different
+    /// machine than the one they describe.
+    pub check_filesystem: bool,
+    /// The directory the system described by the document is mounted at, e.g. `/mnt` when
+    /// installing from a live ISO. Paths (including absolute symlink targets) are resolved
+    /// relative to it when `check_filesystem` is set.
+    pub root: PathBuf,
+}
+
+impl Default for ValidationOptions {
+    fn default() -> Self {
+        Self {
+            store_dir: PathBuf::from(DEFAULT_STORE_DIR),
+            check_filesystem: false,
+            root: PathBuf::from("/"),
+        }
+    }
+}
+
+/// What kind of file a bootspec field must refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileKind {
+    File,
+    Executable,
+    Directory,
+}
+
+/// How serious a [`Diagnostic`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// The document is not usable as-is.
+    Error,
+    /// The document is usable, but likely not what its author intended.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A single problem found during validation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// A JSON pointer (RFC 6901) to the field the problem concerns, e.g.
+    /// `/org.nixos.bootspec.v1/kernel`.
+    pub pointer: String,
+    /// A human-readable description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.pointer, self.message)
+    }
+}
+
+/// The result of validating a bootspec document.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Every problem found, in document order.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ValidationReport {
+    /// The problems that make the document unusable.
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+    }
+
+    /// The problems that do not make the document unusable.
+    pub fn warnings(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Warning)
+    }
+
+    /// Whether the document has no errors. It may still have warnings.
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        severity: Severity,
+        pointer: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            pointer: pointer.into(),
+            message: message.into(),
+        });
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    /// One diagnostic per line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl BootJson {
+    /// Check the document for problems that deserialization does not catch, such as relative
+    /// paths, paths outside the Nix store or kernel parameters that do not match the schema.
+    pub fn validate(&self, options: &ValidationOptions) -> ValidationReport {
+        let mut validator = Validator {
+            options,
+            report: ValidationReport::default(),
+        };
+
+        match &self.generation {
+            Generation::V1(generation) => validator.generation_v1("", generation, &self.extensions),
+        }
+
+        validator.report
+    }
+}
+
+struct Validator<'a> {
+    options: &'a ValidationOptions,
+    report: ValidationReport,
+}
+
+impl Validator<'_> {
+    fn generation_v1(&mut self, pointer: &str, generation: &GenerationV1, extensions: &Extensions) {
+        self.bootspec_v1(
+            &format!("{}/{}", pointer, escape("org.nixos.bootspec.v1")),
+            &generation.bootspec,
+        );
+        self.extensions(pointer, extensions);
+
+        let mut specialisations = generation.specialisations.iter().collect::<Vec<_>>();
+        specialisations.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+        for (name, specialisation) in specialisations {
+            let pointer = format!(
+                "{}/{}/{}",
+                pointer,
+                escape("org.nixos.specialisation.v1"),
+                escape(&name.0)
+            );
+            self.generation_v1(
+                &pointer,
+                &specialisation.generation,
+                &specialisation.extensions,
+            );
+        }
+    }
+
+    fn extensions(&mut self, pointer: &str, extensions: &Extensions) {
+        let mut keys = extensions.keys().collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            let key_pointer = format!("{}/{}", pointer, escape(key));
+            if let Some(version) = unsupported_version(key) {
+                self.report.push(
+                    Severity::Warning,
+                    key_pointer,
+                    format!(
+                        "bootspec version {} is not supported, only version {} is",
+                        version,
+                        v1::SCHEMA_VERSION
+                    ),
+                );
+            } else if extension::is_reserved(key) {
+                self.report.push(
+                    Severity::Error,
+                    key_pointer,
+                    "is not a known bootspec key, but uses a prefix reserved for bootspec",
+                );
+            } else if !extension::is_reverse_domain(key) {
+                self.report.push(
+                    Severity::Warning,
+                    key_pointer,
+                    "is not a reverse-domain extension key such as com.example.extension",
+                );
+            }
+        }
+    }
+
+    fn bootspec_v1(&mut self, pointer: &str, bootspec: &BootSpecV1) {
+        if bootspec.label.trim().is_empty() {
+            self.report
+                .push(Severity::Warning, format!("{}/label", pointer), "is empty");
+        }
+
+        if bootspec.system.is_empty() {
+            self.report
+                .push(Severity::Error, format!("{}/system", pointer), "is empty");
+        } else if !bootspec.system.contains('-') {
+            self.report.push(
+                Severity::Warning,
+                format!("{}/system", pointer),
+                format!(
+                    "{:?} is not a Nix system double such as x86_64-linux",
+                    bootspec.system
+                ),
+            );
+        }
+
+        self.store_path(
+            &format!("{}/kernel", pointer),
+            &bootspec.kernel,
+            FileKind::File,
+        );
+        if self.options.check_filesystem && !bootspec.system.is_empty() {
+            self.kernel_architecture(pointer, bootspec);
+        }
+        self.store_path(
+            &format!("{}/init", pointer),
+            &bootspec.init,
+            FileKind::Executable,
+        );
+        if let Some(initrd) = &bootspec.initrd {
+            self.store_path(&format!("{}/initrd", pointer), initrd, FileKind::File);
+            #[cfg(feature = "initrd")]
+            if self.options.check_filesystem {
+                self.initrd_modules(pointer, bootspec, initrd);
+            }
+        }
+        if let Some(initrd_secrets) = &bootspec.initrd_secrets {
+            self.store_path(
+                &format!("{}/initrdSecrets", pointer),
+                initrd_secrets,
+                FileKind::Executable,
+            );
+            if bootspec.initrd.is_none() {
+                self.report.push(
+                    Severity::Error,
+                    format!("{}/initrdSecrets", pointer),
+                    "is set, but there is no initrd to append secrets to",
+                );
+            }
+        }
+        self.store_path(
+            &format!("{}/toplevel", pointer),
+            &bootspec.toplevel.0,
+            FileKind::Directory,
+        );
+
+        for (i, param) in bootspec.kernel_params.iter().enumerate() {
+            let param_pointer = format!("{}/kernelParams/{}", pointer, i);
+            if param.key().is_empty() {
+                self.report
+                    .push(Severity::Error, param_pointer, "has an empty key");
+            } else if !matches_schema_pattern(param) {
+                self.report.push(
+                    Severity::Error,
+                    param_pointer,
+                    format!(
+                        "{:?} does not match the KernelParameter pattern of the schema",
+                        param.as_str()
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Check that the kernel was built for `system`, if its architecture can be told. Problems
+    /// with the kernel path itself have already been reported by [`Validator::check_file`].
+    fn kernel_architecture(&mut self, pointer: &str, bootspec: &BootSpecV1) {
+        let root = &self.options.root;
+        let Ok(resolved) = resolve_in_root(root, &bootspec.kernel) else {
+            return;
+        };
+        let Ok(image) = kernel::inspect(&reroot(root, &resolved)) else {
+            return;
+        };
+
+        if let Some(architecture) = image.architecture {
+            if !architecture.matches_system(&bootspec.system) {
+                self.report.push(
+                    Severity::Error,
+                    format!("{}/system", pointer),
+                    format!(
+                        "{:?} does not match the kernel, which was built for {}",
+                        bootspec.system, architecture
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Check that the initrd has modules for the kernel, which goes wrong when only one of them
+    /// is rebuilt. Initrds without any modules are accepted, since the kernel may not need any.
+    #[cfg(feature = "initrd")]
+    fn initrd_modules(&mut self, pointer: &str, bootspec: &BootSpecV1, initrd: &Path) {
+        let root = &self.options.root;
+        let kernel_version = resolve_in_root(root, &bootspec.kernel)
+            .ok()
+            .and_then(|kernel| kernel::inspect(&reroot(root, &kernel)).ok())
+            .and_then(|image| image.version)
+            .or_else(|| kernel_version_from_label(&bootspec.label).map(str::to_string));
+        let Some(kernel_version) = kernel_version else {
+            return;
+        };
+        // Problems with the path itself have already been reported by `check_file`.
+        let Ok(contents) = resolve_in_root(root, initrd)
+            .map_err(BootspecError::from)
+            .and_then(|resolved| Initrd::read(&reroot(root, &resolved)))
+        else {
+            return;
+        };
+
+        let pointer = format!("{}/initrd", pointer);
+        match contents.kernel_module_versions() {
+            Ok(versions) if !versions.is_empty() && !versions.contains(&kernel_version) => {
+                self.report.push(
+                    Severity::Error,
+                    pointer,
+                    format!(
+                        "{} has modules for {}, but not for the kernel ({})",
+                        initrd.display(),
+                        versions.join(", "),
+                        kernel_version
+                    ),
+                )
+            }
+            Ok(_) => {}
+            Err(e) => self.report.push(
+                Severity::Error,
+                pointer,
+                format!("{} is not a valid initrd: {}", initrd.display(), e),
+            ),
+        }
+    }
+
+    fn store_path(&mut self, pointer: &str, path: &Path, kind: FileKind) {
+        if !path.is_absolute() {
+            self.report.push(
+                Severity::Error,
+                pointer,
+                format!("{} is not an absolute path", path.display()),
+            );
+            return;
+        }
+
+        if !path.starts_with(&self.options.store_dir) {
+            self.report.push(
+                Severity::Error,
+                pointer,
+                format!(
+                    "{} is not in the Nix store ({})",
+                    path.display(),
+                    self.options.store_dir.display()
+                ),
+            );
+        }
+
+        if self.options.check_filesystem {
+            self.check_file(pointer, path, kind);
+        }
+    }
+
+    fn check_file(&mut self, pointer: &str, path: &Path, kind: FileKind) {
+        let root = &self.options.root;
+        let resolved = match resolve_in_root(root, path) {
+            Ok(resolved) => resolved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.report.push(
+                    Severity::Error,
+                    pointer,
+                    format!("{} does not exist", path.display()),
+                );
+                return;
+            }
+            Err(e) => {
+                self.report.push(
+                    Severity::Error,
+                    pointer,
+                    format!("could not resolve {}: {}", path.display(), e),
+                );
+                return;
+            }
+        };
+
+        if !resolved.starts_with(&self.options.store_dir) {
+            self.report.push(
+                Severity::Error,
+                pointer,
+                format!(
+                    "{} resolves to {}, which is outside the Nix store",
+                    path.display(),
+                    resolved.display()
+                ),
+            );
+        }
+
+        let metadata = match fs::metadata(reroot(root, &resolved)) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                self.report.push(
+                    Severity::Error,
+                    pointer,
+                    format!("could not inspect {}: {}", path.display(), e),
+                );
+                return;
+            }
+        };
+
+        let problem = match kind {
+            FileKind::Directory if !metadata.is_dir() => Some("is not a directory"),
+            FileKind::File | FileKind::Executable if !metadata.is_file() => {
+                Some("is not a regular file")
+            }
+            FileKind::Executable if !is_executable(&metadata) => Some("is not executable"),
+            _ => None,
+        };
+        if let Some(problem) = problem {
+            self.report.push(
+                Severity::Error,
+                pointer,
+                format!("{} {}", path.display(), problem),
+            );
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    true
+}
+
+/// The maximum number of symlinks followed while resolving a single path, matching Linux.
+const MAX_SYMLINKS: usize = 40;
+
+/// Resolve every symlink in the absolute path `path` as if `root` were the root directory, so
+/// that absolute symlink targets (which are common in the Nix store) are looked up under `root`
+/// instead of on the host.
+///
+/// Returns the resolved path, relative to `root` (i.e. as seen from inside it).
+pub(crate) fn resolve_in_root(root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let mut resolved = PathBuf::from("/");
+    let mut pending = Vec::new();
+    push_components(&mut pending, path);
+    let mut symlinks = 0;
+
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&component);
+        let on_disk = reroot(root, &candidate);
+        if fs::symlink_metadata(&on_disk)?.file_type().is_symlink() {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(io::Error::other("too many levels of symbolic links"));
+            }
+
+            let target = fs::read_link(&on_disk)?;
+            if target.is_absolute() {
+                resolved = PathBuf::from("/");
+            }
+            push_components(&mut pending, &target);
+        } else {
+            resolved = candidate;
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Push the normal and `..` components of `path` onto `stack`, so that they are popped in order.
+fn push_components(stack: &mut Vec<OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => stack.push(name.to_os_string()),
+            Component::ParentDir => stack.push(OsString::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+}
+
+/// The location of the absolute path `path` when `root` is the root directory.
+fn reroot(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// The version of an `org.nixos.bootspec.vN` or `org.nixos.specialisation.vN` key, if it is not
+/// supported.
+fn unsupported_version(key: &str) -> Option<u64> {
+    extension::RESERVED_PREFIXES
+        .iter()
+        .filter_map(|prefix| key.strip_prefix(prefix)?.strip_prefix('v'))
+        .find_map(|version| version.parse().ok())
+        .filter(|version| !SUPPORTED_VERSIONS.contains(version))
+}
+
+/// Whether `param` matches the schema's `^[a-zA-Z0-9._-]+(=[^\s=]+)?$` pattern.
+fn matches_schema_pattern(param: &KernelParam) -> bool {
+    let (key, value) = match param.as_str().split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (param.as_str(), None),
+    };
+    let key_valid = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    let value_valid = match value {
+        None => true,
+        Some(value) => !value.is_empty() && !value.chars().any(|c| c.is_whitespace() || c == '='),
+    };
+
+    key_valid && value_valid
+}
+
+/// Extract the kernel version from a label of the form `... (Linux $version)`, as produced by
+/// synthesis and NixOS itself.
+#[cfg(feature = "initrd")]
+fn kernel_version_from_label(label: &str) -> Option<&str> {
+    let (_, version) = label.rsplit_once("(Linux ")?;
+    let version = version.strip_suffix(')')?;
+    (!version.is_empty()).then_some(version)
+}
+
+/// Escape a JSON pointer reference token (RFC 6901).
+pub(crate) fn escape(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use tempfile::TempDir;
+
+    use super::{resolve_in_root, Severity, ValidationOptions};
+    use crate::BootJson;
+
+    const JSON: &str = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/xxx-nixos-system-xxx/init",
+        "initrd": "/nix/store/xxx-initrd-linux/initrd",
+        "kernel": "/nix/store/xxx-linux/bzImage",
+        "kernelParams": ["loglevel=4", "quiet"],
+        "label": "NixOS 21.11 (Linux 5.15.30)",
+        "system": "x86_64-linux",
+        "toplevel": "/nix/store/xxx-nixos-system-xxx"
+    },
+    "org.nixos.specialisation.v1": {
+        "a/b": {
+            "org.nixos.bootspec.v1": {
+                "init": "/nix/store/yyy-nixos-system-yyy/init",
+                "kernel": "bzImage",
+                "kernelParams": ["root=LABEL=nixos", "quiet"],
+                "label": "NixOS 21.11 (Linux 5.15.30)",
+                "system": "x86_64-linux",
+                "toplevel": "/home/user/result",
+                "initrdSecrets": "/nix/store/yyy-nixos-system-yyy/append-initrd-secrets"
+            }
+        }
+    }
+}"#;
+
+    #[test]
+    fn valid_document() {
+        let rfc_json = include_str!("../rfc0125_spec.json");
+        let boot_json: BootJson = serde_json::from_str(rfc_json).unwrap();
+
+        let report = boot_json.validate(&ValidationOptions::default());
+        assert!(report.is_valid());
+        assert!(report.diagnostics.is_empty(), "{:?}", report);
+    }
+
+    #[test]
+    fn invalid_specialisation() {
+        let boot_json: BootJson = serde_json::from_str(JSON).unwrap();
+
+        let report = boot_json.validate(&ValidationOptions::default());
+        assert!(!report.is_valid());
+
+        let diagnostics = report
+            .diagnostics
+            .iter()
+            .map(|d| (d.severity, d.pointer.as_str()))
+            .collect::<Vec<_>>();
+        let prefix = "/org.nixos.specialisation.v1/a~1b/org.nixos.bootspec.v1";
+        assert_eq!(
+            diagnostics,
+            vec![
+                (Severity::Error, format!("{}/kernel", prefix).as_str()),
+                (
+                    Severity::Error,
+                    format!("{}/initrdSecrets", prefix).as_str()
+                ),
+                (Severity::Error, format!("{}/toplevel", prefix).as_str()),
+                (
+                    Severity::Error,
+                    format!("{}/kernelParams/0", prefix).as_str()
+                ),
+            ]
+        );
+        assert_eq!(report.errors().count(), 4);
+        assert_eq!(report.warnings().count(), 0);
+        assert!(report.diagnostics[0]
+            .to_string()
+            .contains("bzImage is not an absolute path"));
+    }
+
+    #[test]
+    fn kernel_params_match_schema() {
+        let mut json: serde_json::Value =
+            serde_json::from_str(include_str!("../rfc0125_spec.json")).unwrap();
+        json["org.nixos.bootspec.v1"]["kernelParams"] = serde_json::json!(["quiet", "foo=bar=baz"]);
+        let boot_json: BootJson = serde_json::from_value(json).unwrap();
+
+        let report = boot_json.validate(&ValidationOptions::default());
+        assert!(!report.is_valid());
+        let diagnostics = report
+            .diagnostics
+            .iter()
+            .map(|d| (d.severity, d.pointer.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            diagnostics,
+            vec![(Severity::Error, "/org.nixos.bootspec.v1/kernelParams/1")]
+        );
+        assert!(report.diagnostics[0]
+            .to_string()
+            .contains("does not match the KernelParameter pattern"));
+    }
+
+    #[test]
+    fn extension_keys() {
+        let mut json: serde_json::Value =
+            serde_json::from_str(include_str!("../rfc0125_spec.json")).unwrap();
+        for key in [
+            "org.nixos.bootspec.v2",
+            "org.nixos.bootspec.vnext",
+            "splash",
+            "com.example.splash.v1",
+        ] {
+            json[key] = serde_json::json!({});
+        }
+        let boot_json: BootJson = serde_json::from_value(json).unwrap();
+
+        let report = boot_json.validate(&ValidationOptions::default());
+        let diagnostics = report
+            .diagnostics
+            .iter()
+            .map(|d| (d.severity, d.pointer.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            diagnostics,
+            vec![
+                (Severity::Warning, "/org.nixos.bootspec.v2"),
+                (Severity::Error, "/org.nixos.bootspec.vnext"),
+                (Severity::Warning, "/splash"),
+            ]
+        );
+        assert!(report.diagnostics[0]
+            .to_string()
+            .contains("bootspec version 2 is not supported"));
+
+        // Unsupported versions survive a round trip instead of being dropped.
+        let json = serde_json::to_string(&boot_json).unwrap();
+        let boot_json: BootJson = serde_json::from_str(&json).unwrap();
+        assert!(boot_json.extensions.contains_key("org.nixos.bootspec.v2"));
+    }
+
+    #[test]
+    fn filesystem_checks_are_opt_in() {
+        let store = TempDir::new().unwrap();
+        let json = JSON.replace("/nix/store", &store.path().display().to_string());
+        let boot_json: BootJson = serde_json::from_str(&json).unwrap();
+        let mut options = ValidationOptions {
+            store_dir: store.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        let without = boot_json.validate(&options);
+        options.check_filesystem = true;
+        let with = boot_json.validate(&options);
+
+        let missing = with
+            .diagnostics
+            .iter()
+            .filter(|d| d.message.ends_with("does not exist"))
+            .map(|d| d.pointer.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            with.diagnostics.len(),
+            without.diagnostics.len() + missing.len()
+        );
+        assert!(missing.contains(&"/org.nixos.bootspec.v1/kernel"));
+        assert!(missing.contains(&"/org.nixos.bootspec.v1/toplevel"));
+    }
+
+    #[test]
+    fn filesystem_checks_against_alternate_root() {
+        let root = TempDir::new().unwrap();
+        let store = root.path().join("nix/store");
+        let executable = |path: &std::path::Path| {
+            fs::write(path, "#!/bin/sh").unwrap();
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+
+        fs::create_dir_all(store.join("xxx-linux/bzImage")).unwrap();
+        fs::create_dir_all(store.join("xxx-nixos-system-xxx")).unwrap();
+        fs::create_dir_all(store.join("xxx-init")).unwrap();
+        executable(&store.join("xxx-init/init"));
+        symlink(
+            "/nix/store/xxx-init/init",
+            store.join("xxx-nixos-system-xxx/init"),
+        )
+        .unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        executable(&root.path().join("etc/secrets"));
+        symlink(
+            "../../../etc/secrets",
+            store.join("xxx-nixos-system-xxx/append-initrd-secrets"),
+        )
+        .unwrap();
+        fs::create_dir_all(store.join("yyy-nixos-system-yyy")).unwrap();
+        fs::write(store.join("yyy-nixos-system-yyy/init"), "").unwrap();
+
+        let json = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/xxx-nixos-system-xxx/init",
+        "initrd": "/nix/store/xxx-initrd-linux/initrd",
+        "initrdSecrets": "/nix/store/xxx-nixos-system-xxx/append-initrd-secrets",
+        "kernel": "/nix/store/xxx-linux/bzImage",
+        "kernelParams": [],
+        "label": "NixOS 21.11 (Linux 5.15.30)",
+        "system": "x86_64-linux",
+        "toplevel": "/nix/store/xxx-nixos-system-xxx"
+    },
+    "org.nixos.specialisation.v1": {
+        "spec": {
+            "org.nixos.bootspec.v1": {
+                "init": "/nix/store/yyy-nixos-system-yyy/init",
+                "kernel": "/nix/store/xxx-linux/bzImage",
+                "kernelParams": [],
+                "label": "NixOS 21.11 (Linux 5.15.30)",
+                "system": "x86_64-linux",
+                "toplevel": "/nix/store/yyy-nixos-system-yyy"
+            }
+        }
+    }
+}"#;
+        let boot_json: BootJson = serde_json::from_str(json).unwrap();
+        let report = boot_json.validate(&ValidationOptions {
+            check_filesystem: true,
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        let messages = report
+            .diagnostics
+            .iter()
+            .map(|d| format!("{}: {}", d.pointer, d.message))
+            .collect::<Vec<_>>();
+        let spec = "/org.nixos.specialisation.v1/spec/org.nixos.bootspec.v1";
+        assert_eq!(
+            messages,
+            vec![
+                "/org.nixos.bootspec.v1/kernel: /nix/store/xxx-linux/bzImage is not a regular file".to_string(),
+                "/org.nixos.bootspec.v1/initrd: /nix/store/xxx-initrd-linux/initrd does not exist".to_string(),
+                "/org.nixos.bootspec.v1/initrdSecrets: /nix/store/xxx-nixos-system-xxx/append-initrd-secrets resolves to /etc/secrets, which is outside the Nix store".to_string(),
+                format!("{}/kernel: /nix/store/xxx-linux/bzImage is not a regular file", spec),
+                format!("{}/init: /nix/store/yyy-nixos-system-yyy/init is not executable", spec),
+            ]
+        );
+    }
+
+    #[test]
+    fn kernel_architecture_matches_system() {
+        let root = TempDir::new().unwrap();
+        let store = root.path().join("nix/store");
+        fs::create_dir_all(store.join("xxx-linux")).unwrap();
+        let mut image = vec![0; 0x40];
+        image[0x38..0x3c].copy_from_slice(b"ARM\x64");
+        fs::write(store.join("xxx-linux/Image"), image).unwrap();
+
+        let json = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/xxx-nixos-system-xxx/init",
+        "kernel": "/nix/store/xxx-linux/Image",
+        "kernelParams": [],
+        "label": "NixOS 21.11 (Linux 5.15.30)",
+        "system": "x86_64-linux",
+        "toplevel": "/nix/store/xxx-nixos-system-xxx"
+    }
+}"#;
+        let mut boot_json: BootJson = serde_json::from_str(json).unwrap();
+        let options = ValidationOptions {
+            check_filesystem: true,
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        };
+
+        let report = boot_json.validate(&options);
+        let mismatch = report
+            .diagnostics
+            .iter()
+            .find(|d| d.pointer == "/org.nixos.bootspec.v1/system")
+            .expect("the system does not match the kernel");
+        assert_eq!(mismatch.severity, Severity::Error);
+        assert_eq!(
+            mismatch.message,
+            "\"x86_64-linux\" does not match the kernel, which was built for aarch64"
+        );
+
+        let crate::generation::Generation::V1(generation) = &mut boot_json.generation;
+        generation.bootspec.system = "aarch64-linux".into();
+        let report = boot_json.validate(&options);
+        assert!(report
+            .diagnostics
+            .iter()
+            .all(|d| d.pointer != "/org.nixos.bootspec.v1/system"));
+    }
+
+    #[cfg(feature = "initrd")]
+    #[test]
+    fn initrd_modules_match_kernel() {
+        use crate::initrd::tests::cpio;
+
+        let root = TempDir::new().unwrap();
+        let store = root.path().join("nix/store");
+        fs::create_dir_all(store.join("xxx-linux")).unwrap();
+        fs::create_dir_all(store.join("xxx-initrd-linux")).unwrap();
+        fs::write(store.join("xxx-linux/bzImage"), "").unwrap();
+        let initrd = store.join("xxx-initrd-linux/initrd");
+
+        let json = r#"{
+    "org.nixos.bootspec.v1": {
+        "init": "/nix/store/xxx-nixos-system-xxx/init",
+        "initrd": "/nix/store/xxx-initrd-linux/initrd",
+        "kernel": "/nix/store/xxx-linux/bzImage",
+        "kernelParams": [],
+        "label": "NixOS 21.11 (Linux 5.15.30)",
+        "system": "x86_64-linux",
+        "toplevel": "/nix/store/xxx-nixos-system-xxx"
+    }
+}"#;
+        let boot_json: BootJson = serde_json::from_str(json).unwrap();
+        let options = ValidationOptions {
+            check_filesystem: true,
+            root: root.path().to_path_buf(),
+            ..Default::default()
+        };
+        let initrd_messages = |contents: &[u8]| {
+            fs::write(&initrd, contents).unwrap();
+            boot_json
+                .validate(&options)
+                .diagnostics
+                .into_iter()
+                .filter(|d| d.pointer == "/org.nixos.bootspec.v1/initrd")
+                .map(|d| d.message)
+                .collect::<Vec<_>>()
+        };
+
+        // The kernel version comes from the label, as the kernel image is empty.
+        assert!(initrd_messages(&cpio(&[("lib/modules/5.15.30", 0o40555, b"")])).is_empty());
+        assert!(initrd_messages(&cpio(&[("init", 0o100755, b"")])).is_empty());
+        assert_eq!(
+            initrd_messages(&cpio(&[("lib/modules/5.15.31", 0o40555, b"")])),
+            vec![
+                "/nix/store/xxx-initrd-linux/initrd has modules for 5.15.31, but not for the kernel (5.15.30)"
+            ]
+        );
+        assert_eq!(
+            initrd_messages(b"junk"),
+            vec!["/nix/store/xxx-initrd-linux/initrd is not a valid initrd: unrecognised data at offset 0"]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "initrd")]
+    fn kernel_version_from_label() {
+        assert_eq!(
+            super::kernel_version_from_label("NixOS 21.11.20210810.dirty (Linux 5.15.30)"),
+            Some("5.15.30")
+        );
+        assert_eq!(super::kernel_version_from_label("NixOS 21.11"), None);
+    }
+
+    #[test]
+    fn resolution_stays_inside_root() {
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("a")).unwrap();
+        fs::write(root.path().join("a/file"), "").unwrap();
+        symlink("/a", root.path().join("absolute")).unwrap();
+        symlink("../../../../../a", root.path().join("escaping")).unwrap();
+        symlink("loop", root.path().join("loop")).unwrap();
+
+        assert_eq!(
+            resolve_in_root(root.path(), "/absolute/file".as_ref()).unwrap(),
+            std::path::Path::new("/a/file")
+        );
+        assert_eq!(
+            resolve_in_root(root.path(), "/escaping/file".as_ref()).unwrap(),
+            std::path::Path::new("/a/file")
+        );
+        assert!(resolve_in_root(root.path(), "/loop".as_ref()).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Documents that carry several bootspec versions at once.
+//!
+//! A `boot.json` may contain a generation for more than one version (e.g. both
+//! `org.nixos.bootspec.v1` and `org.nixos.bootspec.v2`), so that bootloaders which only
+//! understand an older version keep working. [`BootJson`] only keeps the version it selected;
+//! [`BootJsonVersions`] keeps all of them.
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::de::Error as _;
+use serde::ser::{Error as _, SerializeMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::deser;
+use crate::error::BootspecError;
+use crate::generation::Generation;
+use crate::{v1, BootJson, Extensions, Result};
+
+/// The bootspec versions this crate supports, in ascending order.
+pub const SUPPORTED_VERSIONS: &[u64] = &[v1::SCHEMA_VERSION];
+
+/// The top-level key the bootspec of `version` is stored under, e.g. `org.nixos.bootspec.v1`.
+pub fn bootspec_key(version: u64) -> String {
+    format!("org.nixos.bootspec.v{}", version)
+}
+
+/// The top-level key the specialisations of `version` are stored under, e.g.
+/// `org.nixos.specialisation.v1`.
+pub fn specialisation_key(version: u64) -> String {
+    format!("org.nixos.specialisation.v{}", version)
+}
+
+/// A bootspec document with every version it contains.
+///
+/// Versions this crate supports are deserialized into [`BootJsonVersions::generations`]. The keys
+/// of any other version are kept verbatim in [`BootJsonVersions::extensions`], so that they are
+/// written back out unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootJsonVersions {
+    /// The supported versions present in the document, by version number.
+    pub generations: BTreeMap<u64, Generation>,
+    /// Every other top-level key, including those of unsupported versions.
+    pub extensions: Extensions,
+}
+
+impl BootJsonVersions {
+    /// Synthesize a document containing each of `versions` from the path to a generation.
+    ///
+    /// See also [`BootJson::synthesize_version`].
+    pub fn synthesize(generation_path: &Path, versions: &[u64]) -> Result<Self> {
+        let mut document = Self::default();
+        for &version in versions {
+            let boot_json = BootJson::synthesize_version(generation_path, version)?;
+            document.insert(boot_json.generation);
+        }
+
+        Ok(document)
+    }
+
+    /// Add `generation`, returning the generation of the same version it replaces, if any.
+    pub fn insert(&mut self, generation: Generation) -> Option<Generation> {
+        self.generations.insert(generation.version(), generation)
+    }
+
+    /// The highest supported version in the document.
+    pub fn latest(&self) -> Option<&Generation> {
+        self.generations.values().next_back()
+    }
+
+    /// The versions in the document that this crate does not support, in ascending order.
+    pub fn unsupported_versions(&self) -> Vec<u64> {
+        let mut versions = self
+            .extensions
+            .keys()
+            .filter_map(|key| key.strip_prefix("org.nixos.bootspec.v")?.parse().ok())
+            .filter(|version| !SUPPORTED_VERSIONS.contains(version))
+            .collect::<Vec<u64>>();
+        versions.sort_unstable();
+        versions
+    }
+
+    /// Select the highest supported version, as [`BootJson`] does when deserializing.
+    ///
+    /// The other supported versions are dropped; the returned document's extensions still contain
+    /// any unsupported versions.
+    pub fn select(mut self) -> Option<BootJson> {
+        let (_, generation) = self.generations.pop_last()?;
+        Some(BootJson {
+            generation,
+            extensions: self.extensions,
+        })
+    }
+}
+
+impl From<BootJson> for BootJsonVersions {
+    fn from(boot_json: BootJson) -> Self {
+        let mut document = Self {
+            generations: BTreeMap::new(),
+            extensions: boot_json.extensions,
+        };
+        document.insert(boot_json.generation);
+        document
+    }
+}
+
+impl Serialize for BootJsonVersions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut fields = BTreeMap::new();
+        for generation in self.generations.values() {
+            match serde_json::to_value(generation).map_err(S::Error::custom)? {
+                serde_json::Value::Object(map) => fields.extend(map),
+                _ => return Err(S::Error::custom("a generation must serialize to an object")),
+            }
+        }
+
+        let mut map = serializer.serialize_map(None)?;
+        for (key, value) in &fields {
+            map.serialize_entry(key, value)?;
+        }
+        // Keys written by a generation take precedence over stale copies in the extensions.
+        let mut extensions = self
+            .extensions
+            .iter()
+            .filter(|(key, _)| !fields.contains_key(*key))
+            .collect::<Vec<_>>();
+        extensions.sort_by_key(|(key, _)| *key);
+        for (key, value) in extensions {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for BootJsonVersions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let mut fields = serde_json::Map::deserialize(deserializer)?;
+
+        let mut generations = BTreeMap::new();
+        for &version in SUPPORTED_VERSIONS {
+            if !fields.contains_key(&bootspec_key(version)) {
+                continue;
+            }
+
+            let generation = Generation::from_fields(version, &fields).map_err(D::Error::custom)?;
+            fields.remove(&bootspec_key(version));
+            fields.remove(&specialisation_key(version));
+            generations.insert(version, generation);
+        }
+
+        let extensions = deser::skip_generation_fields(serde_json::Value::Object(fields))
+            .map_err(D::Error::custom)?;
+        let document = Self {
+            generations,
+            extensions,
+        };
+
+        if document.generations.is_empty() {
+            return Err(D::Error::custom(BootspecError::NoSupportedVersion(
+                document.unsupported_versions(),
+            )));
+        }
+
+        Ok(document)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BootJsonVersions;
+    use crate::BootJson;
+
+    const V2: &str = r#"{
+        "kernel": "/nix/store/xxx-linux/bzImage",
+        "cmdline": "init=/nix/store/xxx-nixos-system-xxx/init quiet"
+    }"#;
+
+    #[test]
+    fn keeps_every_version() {
+        let mut json: serde_json::Value =
+            serde_json::from_str(include_str!("../rfc0125_spec.json")).unwrap();
+        json["org.nixos.bootspec.v2"] = serde_json::from_str(V2).unwrap();
+
+        let document: BootJsonVersions = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(document.generations.keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(document.latest().unwrap().version(), 1);
+        assert_eq!(document.unsupported_versions(), vec![2]);
+        assert!(!document.extensions.contains_key("org.nixos.bootspec.v1"));
+
+        // Both versions are written back out.
+        let serialized = serde_json::to_value(&document).unwrap();
+        assert_eq!(
+            serialized["org.nixos.bootspec.v1"],
+            json["org.nixos.bootspec.v1"]
+        );
+        assert_eq!(
+            serialized["org.nixos.bootspec.v2"],
+            json["org.nixos.bootspec.v2"]
+        );
+        assert_eq!(
+            serde_json::from_value::<BootJsonVersions>(serialized).unwrap(),
+            document
+        );
+
+        let boot_json = document.clone().select().unwrap();
+        let from_json: BootJson = serde_json::from_value(json).unwrap();
+        assert_eq!(boot_json, from_json);
+        assert_eq!(BootJsonVersions::from(boot_json), document);
+    }
+
+    #[test]
+    fn requires_a_supported_version() {
+        let json = format!(r#"{{ "org.nixos.bootspec.v2": {} }}"#, V2);
+
+        let err = serde_json::from_str::<BootJsonVersions>(&json).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("no supported bootspec version, found versions [2]"),
+            "{}",
+            err
+        );
+    }
+}
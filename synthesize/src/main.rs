@@ -28,9 +28,12 @@ fn cli() -> Result<(), Box<dyn std::error::Error>> {
     let out_path = args.out_path;
     let version = args.version;
 
-    let versioned_spec = BootJson::synthesize_version(&generation_dir, version)?;
+    let report = BootJson::synthesize_with_report(&generation_dir, version)?;
+    for warning in &report.warnings {
+        writeln!(io::stderr(), "warning: {}", warning)?;
+    }
 
-    let pretty = serde_json::to_string_pretty(&versioned_spec)
+    let pretty = serde_json::to_string_pretty(&report.boot_json)
         .map_err(|e| format!("Failed to make pretty JSON from bootspec:\n{}", e))?;
 
     fs::write(&out_path, pretty)
@@ -2,6 +2,8 @@ use std::fs;
 use std::io::{self, Write};
 use std::path::PathBuf;
 
+use bootspec::generation::UnknownFields;
+use bootspec::validation::ValidationOptions;
 use bootspec::BootJson;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -15,12 +17,52 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 fn cli() -> Result<(), Box<dyn std::error::Error>> {
-    let Args { bootspec_path } = parse_args()?;
+    let Args {
+        bootspec_path,
+        strict,
+    } = parse_args()?;
 
     let contents = fs::read_to_string(&bootspec_path)?;
+    let document: serde_json::Value = serde_json::from_str(&contents).map_err(|err| {
+        format!(
+            "Bootspec document at '{}' DOES NOT CONTAIN valid JSON:\n{}",
+            bootspec_path.display(),
+            err
+        )
+    })?;
 
-    match serde_json::from_str::<BootJson>(&contents) {
+    let violations = bootspec::schema::validate(&document);
+    if !violations.is_valid() {
+        return Err(format!(
+            "Bootspec document at '{}' DOES NOT CONTAIN a valid document:\n{}",
+            bootspec_path.display(),
+            violations
+        )
+        .into());
+    }
+
+    let unknown_fields = if strict {
+        UnknownFields::Deny
+    } else {
+        UnknownFields::Drop
+    };
+
+    match BootJson::from_value_with(document, unknown_fields) {
         Ok(boot_json) => {
+            let report = boot_json.validate(&ValidationOptions::default());
+            for diagnostic in &report.diagnostics {
+                writeln!(io::stderr(), "{}", diagnostic)?;
+            }
+
+            if !report.is_valid() {
+                return Err(format!(
+                    "Bootspec document at '{}' DOES NOT CONTAIN a valid document: {} error(s) found.",
+                    bootspec_path.display(),
+                    report.errors().count()
+                )
+                .into());
+            }
+
             let generation = boot_json.generation;
             writeln!(
                 io::stdout(),
@@ -44,20 +86,30 @@ fn cli() -> Result<(), Box<dyn std::error::Error>> {
 
 pub struct Args {
     pub bootspec_path: PathBuf,
+    /// Reject unknown fields inside the bootspec object.
+    pub strict: bool,
 }
 
 fn parse_args() -> Result<Args, Box<dyn std::error::Error>> {
-    let mut args = std::env::args().skip(1);
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    let strict = args.first().map(String::as_str) == Some("--strict");
+    if strict {
+        args.remove(0);
+    }
 
     if args.len() != 1 {
-        writeln!(io::stderr(), "Usage: validate <bootspec_path>")?;
+        writeln!(io::stderr(), "Usage: validate [--strict] <bootspec_path>")?;
         std::process::exit(1);
     }
 
     let bootspec_path = args
-        .next()
+        .pop()
         .ok_or("Expected path to bootspec document, got none.")?
         .parse::<PathBuf>()?;
 
-    Ok(Args { bootspec_path })
+    Ok(Args {
+        bootspec_path,
+        strict,
+    })
 }